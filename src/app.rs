@@ -1,5 +1,6 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
+use matrix_sdk::ruma::{OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedUserId, UserId};
 use ratatui::{
     crossterm::event::{self, Event, KeyCode, KeyModifiers},
     prelude::*,
@@ -7,9 +8,23 @@ use ratatui::{
 };
 use strum::EnumIter;
 
-use crate::{matrix::{self, get_matrix_client}, pos::{get_nearest_focus_area, get_top_left_focus_area}, save::Saving};
+use crate::{
+    devices,
+    emoji,
+    matrix::{self, get_matrix_client, InviteItem, LoginFlows, MatrixClient, RoomItem, Session, SsoPrompt},
+    register::{self, RegisterStage, Registration},
+    rooms::{self, NewRoom},
+    pos::{get_nearest_focus_area, get_top_left_focus_area},
+    save::{Account, Saving, SAVING},
+    spaces::{self, HierarchyRoom, TreeRow},
+    timeline::{self, SendState, Timeline, TimelineItem},
+    uiaa::{self, UiaaDialog, UiaaResponse, UiaaStage},
+    vault::VaultHeader,
+    verification::{self, VerificationDialog, VerificationStage},
+    widgets::{button_block, popup_area}
+};
 
-const FOCUSED_COLOR: ratatui::prelude::Color = Color::Yellow;
+pub const FOCUSED_COLOR: ratatui::prelude::Color = Color::Yellow;
 
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
 enum InputMode {
@@ -21,6 +36,33 @@ enum InputMode {
 enum CurrentScreen {
     #[default] Login,
     Main,
+    // 启动时输入口令解锁保险库
+    Unlock,
+    // 设置或更换保险库口令
+    Vault,
+    Register,
+    Devices,
+    CreateRoom,
+}
+
+// 主界面上与房间相关的弹窗
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum RoomDialog {
+    Join,
+    // 搜索用户并开始私聊
+    NewDm,
+    // 浏览空间中的所有房间
+    Hierarchy,
+    // 确认离开房间
+    Leave(OwnedRoomId),
+    // 确认删除消息
+    Redact(OwnedEventId),
+    // 选择表情回应消息
+    React(OwnedEventId),
+    // 确认退出当前账号
+    Logout,
+    // 确认退出当前账号的其他所有会话
+    LogoutOthers,
 }
 
 #[derive(Debug, Default, PartialEq, Eq, Hash, Clone, EnumIter)]
@@ -28,7 +70,60 @@ pub enum FocusArea {
     #[default] ServerInput,
     UsernameInput,
     PasswordInput,
-    LoginBt
+    LoginBt,
+    // 第几个 SSO 身份提供方
+    SsoBt(usize),
+    TokenInput,
+    TokenBt,
+    AccountList,
+    RoomList,
+    Timeline,
+    Composer,
+    VerificationConfirm,
+    VerificationCancel,
+    VaultPassphraseInput,
+    VaultBt,
+    RegisterBt,
+    RegistrationTokenInput,
+    EmailInput,
+    RegisterConfirm,
+    RegisterCancel,
+    DeviceList,
+    DeviceNameInput,
+    DeviceRenameBt,
+    DeviceDeleteBt,
+    DeviceVerifyBt,
+    UiaaPasswordInput,
+    UiaaConfirm,
+    UiaaCancel,
+    SsoCancel,
+    InviteList,
+    InviteAccept,
+    InviteDecline,
+    InviteIgnore,
+    JoinInput,
+    JoinBt,
+    RoomNameInput,
+    RoomTopicInput,
+    RoomInviteesInput,
+    RoomPublicToggle,
+    RoomEncryptedToggle,
+    CreateRoomBt,
+    LeaveBt,
+    LeaveForgetBt,
+    LogoutBt,
+    LogoutOthersBt,
+    RedactReasonInput,
+    RedactBt,
+    RoomDialogCancel,
+    DmList,
+    UserSearchInput,
+    UserSearchBt,
+    UserSearchList,
+    HierarchyList,
+    ThreadComposer,
+    ReactionSearchInput,
+    ReactionList,
 }
 
 pub fn handle_events(app: &mut App) {
@@ -49,20 +144,202 @@ pub fn handle_events(app: &mut App) {
                             KeyCode::Enter => {
                                 app.click_focus();
                             },
+                            KeyCode::Char('v') if app.main_hotkeys() => {
+                                tokio::spawn(verification::start_self_verification());
+                            },
+                            KeyCode::Char('a') if app.main_hotkeys() => {
+                                matrix::switch_account();
+                            },
+                            KeyCode::Char('n') if app.main_hotkeys() => {
+                                app.current_screen = CurrentScreen::Login;
+                            },
+                            KeyCode::Char('o') if app.main_hotkeys() => {
+                                app.room_dialog = Some(RoomDialog::Logout);
+                                app.current_focus = FocusArea::RoomDialogCancel;
+                            },
+                            KeyCode::Char('O') if app.main_hotkeys() => {
+                                app.room_dialog = Some(RoomDialog::LogoutOthers);
+                                app.current_focus = FocusArea::RoomDialogCancel;
+                            },
+                            KeyCode::Esc if app.show_uiaa => {
+                                app.respond_uiaa(None);
+                            },
+                            KeyCode::Esc if app.show_sso => {
+                                matrix::cancel_sso();
+                            },
+                            KeyCode::Esc if app.selected_invite.is_some() => {
+                                app.selected_invite = None;
+                            },
+                            KeyCode::Esc if app.room_dialog.is_some() => {
+                                app.close_room_dialog();
+                            },
+                            KeyCode::Esc if app.current_screen == CurrentScreen::Main && app.reply_to.is_some() => {
+                                app.reply_to = None;
+                            },
+                            KeyCode::Esc if app.current_screen == CurrentScreen::Main && app.editing.is_some() => {
+                                app.cancel_editing();
+                            },
+                            KeyCode::Char('r') if app.main_hotkeys() => {
+                                if let Some(event_id) = app.selected_event.clone() {
+                                    app.cancel_editing();
+                                    app.reply_to = Some(event_id);
+                                    app.current_focus = FocusArea::Composer;
+                                }
+                            },
+                            KeyCode::Char('e') if app.main_hotkeys() => {
+                                app.start_editing();
+                            },
+                            KeyCode::Char('+') if app.main_hotkeys() => {
+                                if let Some(event_id) = app.selected_event.clone() {
+                                    app.input_data.remove(&FocusArea::ReactionSearchInput);
+                                    app.reaction_list_state = ListState::default();
+                                    app.room_dialog = Some(RoomDialog::React(event_id));
+                                    app.current_focus = FocusArea::ReactionSearchInput;
+                                }
+                            },
+                            KeyCode::Char('x') if app.main_hotkeys() => {
+                                if let Some(event_id) = app.selected_event.clone() {
+                                    app.room_dialog = Some(RoomDialog::Redact(event_id));
+                                    app.current_focus = FocusArea::RedactReasonInput;
+                                }
+                            },
+                            KeyCode::Char('g') if app.main_hotkeys() => {
+                                app.jump_to_reply_parent();
+                            },
+                            KeyCode::Esc if app.current_screen == CurrentScreen::Main && app.open_thread.is_some() => {
+                                if let Some(room_id) = app.selected_room.clone() {
+                                    timeline::close_thread(room_id);
+                                }
+                                app.current_focus = FocusArea::Timeline;
+                            },
+                            KeyCode::Char('f') if app.main_hotkeys() => {
+                                if app.space_filter.is_some() {
+                                    app.space_filter = None;
+                                } else {
+                                    app.space_filter = app.highlighted_space();
+                                }
+                                app.room_list_state = ListState::default();
+                            },
+                            KeyCode::Char('h') if app.main_hotkeys() => {
+                                if let Some(space_id) = app.highlighted_space().or_else(|| app.space_filter.clone()) {
+                                    app.hierarchy_state = ListState::default();
+                                    app.room_dialog = Some(RoomDialog::Hierarchy);
+                                    app.current_focus = FocusArea::HierarchyList;
+                                    tokio::spawn(spaces::browse_hierarchy(space_id));
+                                }
+                            },
+                            KeyCode::Char('j') if app.main_hotkeys() => {
+                                app.room_dialog = Some(RoomDialog::Join);
+                                app.current_focus = FocusArea::JoinInput;
+                            },
+                            KeyCode::Char('l') if app.main_hotkeys() => {
+                                if let Some(room_id) = app.selected_room.clone() {
+                                    app.room_dialog = Some(RoomDialog::Leave(room_id));
+                                    app.current_focus = FocusArea::RoomDialogCancel;
+                                }
+                            },
+                            KeyCode::Char('m') if app.main_hotkeys() => {
+                                rooms::clear_user_search();
+                                app.user_search_state = ListState::default();
+                                app.room_dialog = Some(RoomDialog::NewDm);
+                                app.current_focus = FocusArea::UserSearchInput;
+                            },
+                            KeyCode::Char('c') if app.main_hotkeys() => {
+                                app.current_screen = CurrentScreen::CreateRoom;
+                                app.current_focus = FocusArea::RoomNameInput;
+                                app.new_room_public = false;
+                                app.new_room_encrypted = true;
+                            },
+                            KeyCode::Esc if app.current_screen == CurrentScreen::CreateRoom => {
+                                app.current_screen = CurrentScreen::Main;
+                            },
+                            KeyCode::Char('d') if app.main_hotkeys() => {
+                                app.current_screen = CurrentScreen::Devices;
+                                app.current_focus = FocusArea::DeviceList;
+                                tokio::spawn(devices::refresh_devices());
+                            },
+                            KeyCode::Esc if app.current_screen == CurrentScreen::Devices => {
+                                app.current_screen = CurrentScreen::Main;
+                            },
+                            KeyCode::Char('p') if app.main_hotkeys() => {
+                                app.current_screen = CurrentScreen::Vault;
+                                app.current_focus = FocusArea::VaultPassphraseInput;
+                            },
+                            KeyCode::Esc if app.current_screen == CurrentScreen::Login && app.active_account.is_some() => {
+                                app.current_screen = CurrentScreen::Main;
+                            },
+                            KeyCode::Char('r') if app.current_screen == CurrentScreen::Login => {
+                                app.current_screen = CurrentScreen::Register;
+                            },
+                            KeyCode::Esc if app.current_screen == CurrentScreen::Register => {
+                                register::cancel();
+                                app.current_screen = CurrentScreen::Login;
+                            },
+                            KeyCode::Esc if app.current_screen == CurrentScreen::Vault => {
+                                app.leave_vault_screen();
+                            },
+                            KeyCode::Down | KeyCode::Up if app.current_focus == FocusArea::InviteList => {
+                                let len = app.invites.len();
+                                move_list_selection(&mut app.invite_list_state, len, key.code);
+                            },
+                            KeyCode::Down | KeyCode::Up if app.current_focus == FocusArea::RoomList => {
+                                let len = app.rooms.len();
+                                move_list_selection(&mut app.room_list_state, len, key.code);
+                            },
+                            KeyCode::Down | KeyCode::Up if app.current_focus == FocusArea::DmList => {
+                                let len = app.dms.len();
+                                move_list_selection(&mut app.dm_list_state, len, key.code);
+                            },
+                            KeyCode::Down | KeyCode::Up if app.current_focus == FocusArea::HierarchyList => {
+                                let len = app.hierarchy.len();
+                                move_list_selection(&mut app.hierarchy_state, len, key.code);
+                            },
+                            KeyCode::Down | KeyCode::Up if app.current_focus == FocusArea::UserSearchList => {
+                                let len = app.user_search.len();
+                                move_list_selection(&mut app.user_search_state, len, key.code);
+                            },
+                            KeyCode::Down | KeyCode::Up if app.current_focus == FocusArea::ReactionList => {
+                                let len = app.reaction_results.len();
+                                move_list_selection(&mut app.reaction_list_state, len, key.code);
+                            },
+                            KeyCode::Down | KeyCode::Up if app.current_focus == FocusArea::AccountList => {
+                                let len = app.saved_accounts.len();
+                                move_list_selection(&mut app.account_list_state, len, key.code);
+                            },
+                            KeyCode::Down | KeyCode::Up if app.current_focus == FocusArea::DeviceList => {
+                                let len = app.devices.len();
+                                move_list_selection(&mut app.device_list_state, len, key.code);
+                            },
+                            // Shift+方向键或翻页键按行滚动
+                            KeyCode::PageUp | KeyCode::PageDown if app.current_focus == FocusArea::Timeline => {
+                                app.scroll_timeline(key.code);
+                            },
+                            KeyCode::Down | KeyCode::Up if app.current_focus == FocusArea::Timeline && key.modifiers == KeyModifiers::SHIFT => {
+                                app.scroll_timeline(key.code);
+                            },
+                            KeyCode::PageUp | KeyCode::PageDown if app.current_focus == FocusArea::ThreadComposer => {
+                                app.scroll_thread(key.code);
+                            },
+                            KeyCode::Down | KeyCode::Up if app.current_focus == FocusArea::ThreadComposer && key.modifiers == KeyModifiers::SHIFT => {
+                                app.scroll_thread(key.code);
+                            },
+                            KeyCode::Down | KeyCode::Up if app.current_focus == FocusArea::Timeline => {
+                                app.move_timeline_selection(key.code);
+                            },
                             KeyCode::Down | KeyCode::Up | KeyCode::Left | KeyCode::Right => {
-                                if let Some(focus_area) = get_nearest_focus_area(&app, key.code) {
+                                if let Some(focus_area) = get_nearest_focus_area(app, key.code) {
                                     app.current_focus = focus_area;
                                 }
                             },
                             KeyCode::Tab => {
-                                if let Some(down_focus_area) = get_nearest_focus_area(&app, KeyCode::Down) {
+                                if let Some(down_focus_area) = get_nearest_focus_area(app, KeyCode::Down) {
                                     app.current_focus = down_focus_area;
                                 } else {
-                                    if let Some(right_focus_area) = get_nearest_focus_area(&app, KeyCode::Right) {
+                                    if let Some(right_focus_area) = get_nearest_focus_area(app, KeyCode::Right) {
                                         app.current_focus = right_focus_area;
                                     } else {
                                         // back to top
-                                        if let Some(top_left_focus_area) = get_top_left_focus_area(&app) {
+                                        if let Some(top_left_focus_area) = get_top_left_focus_area(app) {
                                             app.current_focus = top_left_focus_area;
                                         }
                                     }
@@ -73,6 +350,12 @@ pub fn handle_events(app: &mut App) {
                     },
                     InputMode::Editing => {
                         match key.code {
+                            KeyCode::Enter if app.current_focus == FocusArea::Composer => {
+                                app.send_message();
+                            },
+                            KeyCode::Enter if app.current_focus == FocusArea::ThreadComposer => {
+                                app.send_thread_message();
+                            },
                             KeyCode::Enter => app.input_mode = InputMode::Normal,
                             KeyCode::Char(to_insert) => {
                                 app.enter_char(to_insert);
@@ -91,16 +374,19 @@ pub fn handle_events(app: &mut App) {
                             },
                             _ => {}
                         };
-                        match key.modifiers {
-                            KeyModifiers::CONTROL => {
-                                match key.code {
-                                    KeyCode::Char('u') | KeyCode::Char('h') => {
-                                        app.clear_current_content();
-                                    },
-                                    _ => {}
-                                }
-                            },
-                            _ => {}
+                        // 在登录界面输入完服务器地址后查询其支持的登录方式，注册界面也使用同一个输入框
+                        if app.current_screen == CurrentScreen::Login
+                            && app.input_mode == InputMode::Normal
+                            && app.current_focus == FocusArea::ServerInput {
+                            app.discover_login_flows();
+                        }
+                        if key.modifiers == KeyModifiers::CONTROL {
+                            match key.code {
+                                KeyCode::Char('u') | KeyCode::Char('h') => {
+                                    app.clear_current_content();
+                                },
+                                _ => {}
+                            }
                         }
                     }
                 }
@@ -123,7 +409,7 @@ fn one_line_input_block<'a>(app: &mut App, area: FocusArea, rect: Rect, block: B
         }
     }
 
-    if area == FocusArea::PasswordInput {
+    if matches!(area, FocusArea::PasswordInput | FocusArea::TokenInput | FocusArea::VaultPassphraseInput | FocusArea::UiaaPasswordInput) {
         let mut new_value = String::new();
         for _ in 0..inner_value.len() {
             new_value.push('*');
@@ -131,13 +417,1299 @@ fn one_line_input_block<'a>(app: &mut App, area: FocusArea, rect: Rect, block: B
         inner_value = new_value;
     }
 
-    let inner_area = Paragraph::new(inner_value)
-        .style(s)
-        .block(block.padding(Padding::horizontal(1)));
+    let inner_area = Paragraph::new(inner_value)
+        .style(s)
+        .block(block.padding(Padding::horizontal(1)));
+
+    app.focus_area_positions.insert(area.clone(), rect);
+
+    frame.render_widget(inner_area, rect);
+}
+
+fn account_list_block(app: &mut App, client: &MatrixClient, rect: Rect, frame: &mut Frame) {
+    let mut s = Style::default();
+    if app.current_focus == FocusArea::AccountList {
+        s = s.fg(FOCUSED_COLOR);
+    }
+
+    let items: Vec<ListItem> = app.saved_accounts.iter().map(|account| {
+        let mut spans = vec![account.user_id.clone().into()];
+        if client.is_logged_in(&account.user_id) {
+            spans.push(" (logged in)".green());
+        }
+        ListItem::new(Line::from(spans))
+    }).collect();
+
+    if app.account_list_state.selected().is_none() && !items.is_empty() {
+        app.account_list_state.select(Some(0));
+    }
+
+    let list = List::new(items)
+        .block(Block::bordered().title(" Saved Accounts ").border_style(s))
+        .highlight_symbol("> ")
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+    app.focus_area_positions.insert(FocusArea::AccountList, rect);
+
+    frame.render_stateful_widget(list, rect, &mut app.account_list_state);
+}
+
+fn invite_list_block(app: &mut App, session: &Session, rect: Rect, frame: &mut Frame) {
+    let mut s = Style::default();
+    if app.current_focus == FocusArea::InviteList {
+        s = s.fg(FOCUSED_COLOR);
+    }
+
+    let items: Vec<ListItem> = session.invites.iter().map(|invite| {
+        let mut spans = vec![];
+        if invite.encrypted {
+            spans.push("🔒 ".into());
+        }
+        spans.push(invite.name.clone().bold());
+        if let Some(inviter) = &invite.inviter_name {
+            spans.push(format!(" from {}", inviter).dark_gray());
+        }
+        ListItem::new(Line::from(spans))
+    }).collect();
+
+    if app.invite_list_state.selected().is_none() && !items.is_empty() {
+        app.invite_list_state.select(Some(0));
+    }
+
+    let list = List::new(items)
+        .block(Block::bordered().title(" Invites ").border_style(s))
+        .highlight_symbol("> ")
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+    app.focus_area_positions.insert(FocusArea::InviteList, rect);
+
+    frame.render_stateful_widget(list, rect, &mut app.invite_list_state);
+}
+
+fn room_list_item<'a>(app: &App, room: &RoomItem, depth: usize) -> ListItem<'a> {
+    let mut spans = vec!["  ".repeat(depth).into()];
+    if room.is_space {
+        if app.collapsed_spaces.contains(&room.room_id) {
+            spans.push("▸ ".into());
+        } else {
+            spans.push("▾ ".into());
+        }
+    } else if room.encrypted {
+        spans.push("🔒 ".into());
+    }
+    if app.selected_room.as_ref() == Some(&room.room_id) {
+        spans.push(room.name.clone().bold());
+    } else {
+        spans.push(room.name.clone().into());
+    }
+    if room.unread > 0 {
+        let badge = format!(" ({})", room.unread);
+        if room.highlight > 0 {
+            spans.push(badge.red().bold());
+        } else {
+            spans.push(badge.cyan());
+        }
+    }
+    ListItem::new(Line::from(spans))
+}
+
+fn dm_list_block(app: &mut App, session: &Session, rect: Rect, frame: &mut Frame) {
+    let mut s = Style::default();
+    if app.current_focus == FocusArea::DmList {
+        s = s.fg(FOCUSED_COLOR);
+    }
+
+    let items: Vec<ListItem> = session.rooms.iter()
+        .filter(|room| room.direct)
+        .map(|room| room_list_item(app, room, 0))
+        .collect();
+
+    if app.dm_list_state.selected().is_none() && !items.is_empty() {
+        app.dm_list_state.select(Some(0));
+    }
+
+    let list = List::new(items)
+        .block(Block::bordered().title(" Direct Messages ").border_style(s))
+        .highlight_symbol("> ")
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+    app.focus_area_positions.insert(FocusArea::DmList, rect);
+
+    frame.render_stateful_widget(list, rect, &mut app.dm_list_state);
+}
+
+fn room_list_block(app: &mut App, session: &Session, rect: Rect, frame: &mut Frame) {
+    let mut s = Style::default();
+    if app.current_focus == FocusArea::RoomList {
+        s = s.fg(FOCUSED_COLOR);
+    }
+
+    let items: Vec<ListItem> = app.room_tree.iter()
+        .filter_map(|row| {
+            let room = session.rooms.iter().find(|room| room.room_id == row.room_id)?;
+            Some(room_list_item(app, room, row.depth))
+        })
+        .collect();
+
+    if app.room_list_state.selected().is_none() && !items.is_empty() {
+        app.room_list_state.select(Some(0));
+    }
+
+    // 按空间筛选时在标题中显示空间名
+    let title = match app.space_filter.as_ref()
+        .and_then(|space_id| session.rooms.iter().find(|room| &room.room_id == space_id)) {
+            Some(space) => format!(" {} · {} ", session.user_id, space.name),
+            None => format!(" {} ", session.user_id),
+        };
+
+    let list = List::new(items)
+        .block(
+            Block::bordered()
+                .title(title)
+                .title_bottom(Line::from(format!(" {} ", session.sync_state)).right_aligned())
+                .border_style(s)
+        )
+        .highlight_symbol("> ")
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+    app.focus_area_positions.insert(FocusArea::RoomList, rect);
+
+    frame.render_stateful_widget(list, rect, &mut app.room_list_state);
+}
+
+// 回复上方显示的一行引用
+fn quote_line<'a>(timeline: &Timeline, item: &TimelineItem) -> Option<Line<'a>> {
+    let parent = item.in_reply_to.as_ref()?;
+    let quote = match timeline.find(parent) {
+        Some(parent) if parent.redacted => format!("  ↳ {}: Message deleted", parent.sender),
+        Some(parent) if parent.decryption_error.is_some() => format!("  ↳ {}: Unable to decrypt message", parent.sender),
+        Some(parent) => format!("  ↳ {}: {}", parent.sender, parent.body.lines().next().unwrap_or_default()),
+        None => "  ↳ In reply to an earlier message".to_string(),
+    };
+    Some(Line::from(quote.dark_gray().italic()))
+}
+
+// 一条消息显示的各行，多行消息的后续行缩进显示
+fn message_lines<'a>(timeline: &Timeline, item: &TimelineItem, extra: Vec<Span<'a>>) -> Vec<Line<'a>> {
+    let mut body_lines = item.body.lines();
+    let body = match &item.decryption_error {
+        _ if item.redacted => "Message deleted".dark_gray().italic(),
+        Some(reason) => format!("Unable to decrypt message: {}", reason).dark_gray().italic(),
+        None => body_lines.next().unwrap_or_default().to_string().into(),
+    };
+    let mut spans = vec![
+        item.time().dark_gray(),
+        " ".into(),
+        item.sender.clone().cyan().bold(),
+        ": ".into(),
+        body,
+    ];
+    match &item.send_state {
+        SendState::Sent => {},
+        SendState::Sending => spans.push(" (sending)".dark_gray().italic()),
+        SendState::Failed(e) => spans.push(format!(" (failed: {})", e).red()),
+    }
+    if item.edited {
+        spans.push(" (edited)".dark_gray());
+    }
+    spans.extend(extra);
+
+    let mut lines: Vec<Line> = quote_line(timeline, item).into_iter().collect();
+    lines.push(Line::from(spans));
+    for body_line in body_lines {
+        lines.push(Line::from(format!("    {}", body_line)));
+    }
+
+    // 消息下方的回应，自己回应过的高亮显示
+    let reactions = item.event_id.as_ref().map(|event_id| timeline.reactions(event_id)).unwrap_or_default();
+    if !reactions.is_empty() {
+        let mut spans: Vec<Span> = vec!["    ".into()];
+        for group in reactions {
+            let key: String = group.key.chars().take(16).collect();
+            let label = format!(" {} {} ", key, group.count);
+            spans.push(if group.own { label.black().on_yellow() } else { label.on_dark_gray() });
+            spans.push(" ".into());
+        }
+        lines.push(Line::from(spans));
+    }
+    lines
+}
+
+fn thread_block(app: &mut App, session: &Session, root: &OwnedEventId, rect: Rect, frame: &mut Frame) {
+    let [thread_area, composer_area] = Layout::vertical(vec![
+        Constraint::Min(3),
+        Constraint::Length(3)
+    ]).areas(rect);
+
+    let mut lines: Vec<Line> = vec![];
+    let timeline = app.selected_room.as_ref().and_then(|room_id| session.timelines.get(room_id));
+    if let Some(timeline) = timeline {
+        if timeline.thread_loading {
+            lines.push(Line::from("Loading thread...".italic().dark_gray()).centered());
+        }
+        let items = timeline.thread_items(root);
+        let replies = items.iter().filter(|item| item.thread_root.is_some()).count();
+        for item in items {
+            let is_root = item.event_id.as_ref() == Some(root);
+            lines.extend(message_lines(timeline, item, vec![]));
+            if is_root {
+                lines.push(Line::from(format!("── {} replies ──", replies).dark_gray()).centered());
+            }
+        }
+    }
+
+    // 新回复在底部
+    let height = thread_area.height.saturating_sub(2);
+    app.thread_max_scroll = (lines.len() as u16).saturating_sub(height);
+    app.thread_scroll = app.thread_scroll.min(app.thread_max_scroll);
+    let thread = Paragraph::new(lines)
+        .scroll((app.thread_max_scroll - app.thread_scroll, 0))
+        .block(Block::bordered().title(" Thread "));
+    frame.render_widget(thread, thread_area);
+
+    one_line_input_block(
+        app,
+        FocusArea::ThreadComposer,
+        composer_area,
+        Block::bordered().title(" Reply in thread "),
+        frame
+    );
+}
+
+fn timeline_block(app: &mut App, session: &Session, rect: Rect, frame: &mut Frame) {
+    let mut s = Style::default();
+    if app.current_focus == FocusArea::Timeline {
+        s = s.fg(FOCUSED_COLOR);
+    }
+
+    let Some(room_id) = app.selected_room.clone() else {
+        let block = Block::bordered().border_style(s);
+        frame.render_widget(Paragraph::new("Select a room".italic()).centered().block(block), rect);
+        app.focus_area_positions.insert(FocusArea::Timeline, rect);
+        return;
+    };
+
+    let room_name = session.rooms.iter()
+        .find(|room| room.room_id == room_id)
+        .map(|room| room.name.clone())
+        .unwrap_or_else(|| room_id.to_string());
+
+    let mut lines: Vec<Line> = vec![];
+    // 选中的消息所占的行
+    let mut selected_lines = None;
+    if let Some(timeline) = session.timelines.get(&room_id) {
+        if timeline.paginating {
+            lines.push(Line::from("Loading history...".italic().dark_gray()).centered());
+        } else if timeline.reached_start {
+            lines.push(Line::from("Beginning of the room".italic().dark_gray()).centered());
+        }
+
+        // 话题中的回复只显示在话题侧边栏中
+        for item in timeline.items.iter().filter(|item| item.thread_root.is_none()) {
+            let mut extra = vec![];
+            if item.thread_count > 0 {
+                let replies = if item.thread_count == 1 { "reply" } else { "replies" };
+                extra.push(format!(" 💬 {} {}", item.thread_count, replies).magenta());
+                let unread = item.event_id.as_ref()
+                    .and_then(|event_id| timeline.thread_unread.get(event_id))
+                    .copied()
+                    .unwrap_or_default();
+                if unread > 0 {
+                    extra.push(format!(" ({} new)", unread).red().bold());
+                }
+            }
+
+            let selected = app.selected_event.is_some() && item.event_id == app.selected_event;
+            let start = lines.len();
+            for line in message_lines(timeline, item, extra) {
+                if selected {
+                    lines.push(line.patch_style(Style::default().add_modifier(Modifier::REVERSED)));
+                } else {
+                    lines.push(line);
+                }
+            }
+            if selected {
+                selected_lines = Some((start as u16, lines.len() as u16));
+            }
+        }
+    }
+
+    let height = rect.height.saturating_sub(2);
+    app.timeline_max_scroll = (lines.len() as u16).saturating_sub(height);
+    app.timeline_scroll = app.timeline_scroll.min(app.timeline_max_scroll);
+    // 选中的消息改变后滚动到能看到它的位置，过高的消息显示开头
+    let follow = std::mem::take(&mut app.scroll_to_selection);
+    if let Some((start, end)) = selected_lines.filter(|_| follow) {
+        let mut top = app.timeline_max_scroll - app.timeline_scroll;
+        if end > top + height {
+            top = end.saturating_sub(height);
+        }
+        if start < top {
+            top = start;
+        }
+        app.timeline_scroll = app.timeline_max_scroll - top.min(app.timeline_max_scroll);
+    }
+
+    let timeline = Paragraph::new(lines)
+        .scroll((app.timeline_max_scroll - app.timeline_scroll, 0))
+        .block(Block::bordered().title(format!(" {} ", room_name)).border_style(s));
+
+    app.focus_area_positions.insert(FocusArea::Timeline, rect);
+
+    frame.render_widget(timeline, rect);
+}
+
+fn login_form(app: &mut App, area: Rect, frame: &mut Frame) {
+    // 只显示服务器支持的登录方式
+    let login_flows = app.login_flows.clone();
+    let password = login_flows.as_ref().is_some_and(|flows| flows.password);
+    let token = login_flows.as_ref().is_some_and(|flows| flows.token);
+    let sso = login_flows.as_ref().is_some_and(|flows| flows.sso);
+
+    let mut constraints = vec![Constraint::Length(1), Constraint::Length(3)];
+    if password {
+        constraints.extend([Constraint::Length(3); 3]);
+    }
+    if token {
+        constraints.push(Constraint::Length(3));
+    }
+    if sso {
+        constraints.push(Constraint::Length(3));
+    }
+    if !(password || token || sso) {
+        constraints.push(Constraint::Length(1));
+    }
+
+    let layout = Layout::vertical(constraints)
+        .flex(layout::Flex::Center)
+        .spacing(1)
+        .horizontal_margin(10)
+        .vertical_margin(1)
+        .split(area);
+    let mut rows = layout.iter().copied();
+
+    let login_text = Text::from("Login to your matrix account".bold()).alignment(Alignment::Center);
+    frame.render_widget(login_text, rows.next().unwrap_or_default());
+
+    one_line_input_block(
+        app,
+        FocusArea::ServerInput,
+        rows.next().unwrap_or_default(),
+        Block::bordered().title(" Server Address "),
+        frame
+    );
+
+    if password {
+        one_line_input_block(
+            app,
+            FocusArea::UsernameInput,
+            rows.next().unwrap_or_default(),
+            Block::bordered().title(" Username "),
+            frame
+        );
+
+        one_line_input_block(
+            app,
+            FocusArea::PasswordInput,
+            rows.next().unwrap_or_default(),
+            Block::bordered().title(" Password "),
+            frame
+        );
+
+        let [login_bt_layout] = Layout::horizontal(
+            vec![Constraint::Percentage(10)]
+        ).flex(layout::Flex::Center).areas(rows.next().unwrap_or_default());
+        button_block(app, FocusArea::LoginBt, "Login", login_bt_layout, frame);
+    }
+
+    if token {
+        let [token_area, token_bt_area] = Layout::horizontal(vec![
+            Constraint::Min(1),
+            Constraint::Length(16)
+        ]).spacing(1).areas(rows.next().unwrap_or_default());
+        one_line_input_block(
+            app,
+            FocusArea::TokenInput,
+            token_area,
+            Block::bordered().title(" Login Token "),
+            frame
+        );
+        button_block(app, FocusArea::TokenBt, "Use Token", token_bt_area, frame);
+    }
+
+    if sso {
+        // 每个身份提供方一个按钮，没有列出时使用服务器默认的 SSO
+        let providers: Vec<String> = match &login_flows {
+            Some(flows) if !flows.identity_providers.is_empty() => {
+                flows.identity_providers.iter().map(|(_, name)| name.clone()).collect()
+            },
+            _ => vec!["SSO".to_string()],
+        };
+        let button_areas = Layout::horizontal(vec![Constraint::Length(20); providers.len()])
+            .flex(layout::Flex::Center)
+            .spacing(2)
+            .split(rows.next().unwrap_or_default());
+        for (index, (label, rect)) in providers.iter().zip(button_areas.iter()).enumerate() {
+            button_block(app, FocusArea::SsoBt(index), label, *rect, frame);
+        }
+    }
+
+    if !(password || token || sso) {
+        let hint = if login_flows.is_some() {
+            "This server does not offer any supported login method"
+        } else {
+            "Enter the server address to see its login methods"
+        };
+        frame.render_widget(
+            Paragraph::new(hint.italic().dark_gray()).centered(),
+            rows.next().unwrap_or_default()
+        );
+    }
+}
+
+fn register_form(app: &mut App, registration: Option<&Registration>, area: Rect, frame: &mut Frame) {
+    let Some(registration) = registration else {
+        let layout = Layout::vertical(vec![
+            Constraint::Length(1),
+            Constraint::Length(3),
+            Constraint::Length(3),
+            Constraint::Length(3),
+            Constraint::Length(3)
+        ])
+            .flex(layout::Flex::SpaceBetween)
+            .horizontal_margin(10)
+            .vertical_margin(1);
+
+        let [
+            text_area,
+            server_area,
+            username_area,
+            password_area,
+            register_button_area
+        ] = layout.areas(area);
+
+        let register_text = Text::from("Create a matrix account".bold()).alignment(Alignment::Center);
+        frame.render_widget(register_text, text_area);
+
+        one_line_input_block(
+            app,
+            FocusArea::ServerInput,
+            server_area,
+            Block::bordered().title(" Server Address "),
+            frame
+        );
+
+        one_line_input_block(
+            app,
+            FocusArea::UsernameInput,
+            username_area,
+            Block::bordered().title(" Username "),
+            frame
+        );
+
+        one_line_input_block(
+            app,
+            FocusArea::PasswordInput,
+            password_area,
+            Block::bordered().title(" Password "),
+            frame
+        );
+
+        let [register_bt_layout] = Layout::horizontal(
+            vec![Constraint::Percentage(10)]
+        ).flex(layout::Flex::Center).areas(register_button_area);
+        button_block(app, FocusArea::RegisterBt, "Register", register_bt_layout, frame);
+        return;
+    };
+
+    let layout = Layout::vertical(vec![
+        Constraint::Length(1),
+        Constraint::Min(1),
+        Constraint::Length(3),
+        Constraint::Length(3)
+    ])
+        .flex(layout::Flex::Center)
+        .spacing(1)
+        .horizontal_margin(10)
+        .vertical_margin(1);
+
+    let [text_area, detail_area, input_area, buttons_area] = layout.areas(area);
+
+    let title = format!("Registering {} on {}", registration.username, registration.server);
+    frame.render_widget(Text::from(title.bold()).alignment(Alignment::Center), text_area);
+
+    let confirm = match &registration.stage {
+        RegisterStage::Pending => {
+            frame.render_widget(Paragraph::new("Waiting for the server...".italic()).centered(), detail_area);
+            None
+        },
+        RegisterStage::Terms(policies) => {
+            let mut lines = vec![Line::from("Please review and accept the following terms:")];
+            for (name, url) in policies {
+                lines.push(Line::from(vec![name.clone().bold(), " ".into(), url.clone().underlined()]));
+            }
+            frame.render_widget(Paragraph::new(lines).centered().wrap(Wrap { trim: true }), detail_area);
+            Some("Accept")
+        },
+        RegisterStage::RegistrationToken => {
+            frame.render_widget(Paragraph::new("This server requires a registration token.").centered(), detail_area);
+            one_line_input_block(
+                app,
+                FocusArea::RegistrationTokenInput,
+                input_area,
+                Block::bordered().title(" Registration Token "),
+                frame
+            );
+            Some("Submit")
+        },
+        RegisterStage::Email => {
+            frame.render_widget(Paragraph::new("This server requires an email address.").centered(), detail_area);
+            one_line_input_block(
+                app,
+                FocusArea::EmailInput,
+                input_area,
+                Block::bordered().title(" Email "),
+                frame
+            );
+            Some("Send Email")
+        },
+        RegisterStage::EmailSent(email) => {
+            let text = format!("A verification link has been sent to {}.\nContinue after opening the link.", email);
+            frame.render_widget(Paragraph::new(text).centered().wrap(Wrap { trim: true }), detail_area);
+            Some("Continue")
+        },
+    };
+
+    let buttons: Vec<(FocusArea, &str)> = [
+        confirm.map(|label| (FocusArea::RegisterConfirm, label)),
+        Some((FocusArea::RegisterCancel, "Cancel")),
+    ].into_iter().flatten().collect();
+
+    let button_areas = Layout::horizontal(vec![Constraint::Length(20); buttons.len()])
+        .flex(layout::Flex::Center)
+        .spacing(2)
+        .split(buttons_area);
+    for ((focus_area, label), rect) in buttons.into_iter().zip(button_areas.iter()) {
+        button_block(app, focus_area, label, *rect, frame);
+    }
+}
+
+fn vault_form(app: &mut App, unlock: bool, area: Rect, frame: &mut Frame) {
+    let layout = Layout::vertical(vec![
+        Constraint::Length(1),
+        Constraint::Length(3),
+        Constraint::Length(3)
+    ])
+        .flex(layout::Flex::SpaceAround)
+        .horizontal_margin(10)
+        .vertical_margin(1);
+
+    let [text_area, passphrase_area, button_area] = layout.areas(area);
+
+    let (text, label) = if unlock {
+        ("Enter the passphrase to unlock saved accounts", "Unlock")
+    } else {
+        ("Set a passphrase to encrypt saved credentials", "Save")
+    };
+    frame.render_widget(Text::from(text.bold()).alignment(Alignment::Center), text_area);
+
+    one_line_input_block(
+        app,
+        FocusArea::VaultPassphraseInput,
+        passphrase_area,
+        Block::bordered().title(" Passphrase "),
+        frame
+    );
+
+    let [bt_layout] = Layout::horizontal(
+        vec![Constraint::Percentage(10)]
+    ).flex(layout::Flex::Center).areas(button_area);
+    button_block(app, FocusArea::VaultBt, label, bt_layout, frame);
+}
+
+fn device_list_block(app: &mut App, session: &Session, rect: Rect, frame: &mut Frame) {
+    let mut s = Style::default();
+    if app.current_focus == FocusArea::DeviceList {
+        s = s.fg(FOCUSED_COLOR);
+    }
+
+    let items: Vec<ListItem> = session.devices.iter().map(|device| {
+        let mut spans = vec![
+            device.device_id.to_string().bold(),
+            " ".into(),
+            device.display_name.clone().unwrap_or_default().into(),
+            " ".into(),
+            device.last_seen_ip.clone().unwrap_or_default().dark_gray(),
+            " ".into(),
+            device.last_seen().dark_gray(),
+            " ".into(),
+        ];
+        if device.verified {
+            spans.push("verified".green());
+        } else {
+            spans.push("unverified".red());
+        }
+        if device.current {
+            spans.push(" (this session)".cyan());
+        }
+        ListItem::new(Line::from(spans))
+    }).collect();
+
+    if app.device_list_state.selected().is_none() && !items.is_empty() {
+        app.device_list_state.select(Some(0));
+    }
+
+    let list = List::new(items)
+        .block(Block::bordered().title(format!(" Devices of {} ", session.user_id)).border_style(s))
+        .highlight_symbol("> ")
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+    app.focus_area_positions.insert(FocusArea::DeviceList, rect);
+
+    frame.render_stateful_widget(list, rect, &mut app.device_list_state);
+}
+
+fn devices_screen(app: &mut App, session: &Session, area: Rect, frame: &mut Frame) {
+    let [list_area, rename_area, buttons_area] = Layout::vertical(vec![
+        Constraint::Min(3),
+        Constraint::Length(3),
+        Constraint::Length(3)
+    ]).areas(area);
+
+    device_list_block(app, session, list_area, frame);
+
+    let [name_area, rename_bt_area] = Layout::horizontal(vec![
+        Constraint::Min(1),
+        Constraint::Length(16)
+    ]).spacing(1).areas(rename_area);
+    one_line_input_block(
+        app,
+        FocusArea::DeviceNameInput,
+        name_area,
+        Block::bordered().title(" New Name "),
+        frame
+    );
+    button_block(app, FocusArea::DeviceRenameBt, "Rename", rename_bt_area, frame);
+
+    let [verify_bt_area, delete_bt_area] = Layout::horizontal(vec![Constraint::Length(16); 2])
+        .flex(layout::Flex::Center)
+        .spacing(2)
+        .areas(buttons_area);
+    button_block(app, FocusArea::DeviceVerifyBt, "Verify", verify_bt_area, frame);
+    button_block(app, FocusArea::DeviceDeleteBt, "Delete", delete_bt_area, frame);
+}
+
+fn invite_dialog(app: &mut App, invite: &InviteItem, area: Rect, frame: &mut Frame) {
+    let popup = popup_area(area, 70, 10, frame);
+    let block = Block::bordered().title(" Invitation ").padding(Padding::horizontal(1));
+    let inner = block.inner(popup);
+    frame.render_widget(block, popup);
+
+    let [text_area, buttons_area] = Layout::vertical(vec![
+        Constraint::Min(1),
+        Constraint::Length(3)
+    ]).areas(inner);
+
+    let inviter = match (&invite.inviter_name, &invite.inviter) {
+        (Some(name), Some(user_id)) => format!("{} ({})", name, user_id),
+        _ => "Someone".to_string(),
+    };
+    let mut lines = vec![
+        Line::from(format!("{} invited you to", inviter)),
+        Line::from(invite.name.clone().bold()),
+    ];
+    if invite.encrypted {
+        lines.push(Line::from("This room is encrypted.".dark_gray()));
+    }
+    frame.render_widget(Paragraph::new(lines).centered().wrap(Wrap { trim: true }), text_area);
+
+    let buttons: Vec<(FocusArea, &str)> = [
+        Some((FocusArea::InviteAccept, "Accept")),
+        Some((FocusArea::InviteDecline, "Decline")),
+        invite.inviter.as_ref().map(|_| (FocusArea::InviteIgnore, "Decline & Ignore")),
+    ].into_iter().flatten().collect();
+
+    let button_areas = Layout::horizontal(vec![Constraint::Length(20); buttons.len()])
+        .flex(layout::Flex::Center)
+        .spacing(2)
+        .split(buttons_area);
+    for ((focus_area, label), rect) in buttons.into_iter().zip(button_areas.iter()) {
+        button_block(app, focus_area, label, *rect, frame);
+    }
+}
+
+fn create_room_form(app: &mut App, area: Rect, frame: &mut Frame) {
+    let layout = Layout::vertical(vec![
+        Constraint::Length(1),
+        Constraint::Length(3),
+        Constraint::Length(3),
+        Constraint::Length(3),
+        Constraint::Length(3),
+        Constraint::Length(3)
+    ])
+        .flex(layout::Flex::SpaceBetween)
+        .horizontal_margin(10)
+        .vertical_margin(1);
+
+    let [
+        text_area,
+        name_area,
+        topic_area,
+        invitees_area,
+        toggles_area,
+        create_button_area
+    ] = layout.areas(area);
+
+    frame.render_widget(Text::from("Create a room".bold()).alignment(Alignment::Center), text_area);
+
+    one_line_input_block(
+        app,
+        FocusArea::RoomNameInput,
+        name_area,
+        Block::bordered().title(" Name "),
+        frame
+    );
+
+    one_line_input_block(
+        app,
+        FocusArea::RoomTopicInput,
+        topic_area,
+        Block::bordered().title(" Topic "),
+        frame
+    );
+
+    one_line_input_block(
+        app,
+        FocusArea::RoomInviteesInput,
+        invitees_area,
+        Block::bordered().title(" Invite (user IDs separated by spaces) "),
+        frame
+    );
+
+    // 开关按钮，按回车切换
+    let checkbox = |checked: bool, label: &str| {
+        format!("[{}] {}", if checked { "x" } else { " " }, label)
+    };
+    let public = checkbox(app.new_room_public, "Public");
+    let encrypted = checkbox(app.new_room_encrypted, "Encrypted");
+    let [public_area, encrypted_area] = Layout::horizontal(vec![Constraint::Length(20); 2])
+        .flex(layout::Flex::Center)
+        .spacing(2)
+        .areas(toggles_area);
+    button_block(app, FocusArea::RoomPublicToggle, &public, public_area, frame);
+    button_block(app, FocusArea::RoomEncryptedToggle, &encrypted, encrypted_area, frame);
+
+    let [create_bt_layout] = Layout::horizontal(
+        vec![Constraint::Length(20)]
+    ).flex(layout::Flex::Center).areas(create_button_area);
+    button_block(app, FocusArea::CreateRoomBt, "Create", create_bt_layout, frame);
+}
+
+fn new_dm_dialog(app: &mut App, session: &Session, area: Rect, frame: &mut Frame) {
+    let popup = popup_area(area, 70, 18, frame);
+    let block = Block::bordered().title(" New Direct Message ").padding(Padding::horizontal(1));
+    let inner = block.inner(popup);
+    frame.render_widget(block, popup);
+
+    let [search_area, list_area, buttons_area] = Layout::vertical(vec![
+        Constraint::Length(3),
+        Constraint::Min(3),
+        Constraint::Length(3)
+    ]).areas(inner);
+
+    let [input_area, search_bt_area] = Layout::horizontal(vec![
+        Constraint::Min(1),
+        Constraint::Length(16)
+    ]).spacing(1).areas(search_area);
+    one_line_input_block(
+        app,
+        FocusArea::UserSearchInput,
+        input_area,
+        Block::bordered().title(" Name or user ID "),
+        frame
+    );
+    button_block(app, FocusArea::UserSearchBt, "Search", search_bt_area, frame);
+
+    let mut s = Style::default();
+    if app.current_focus == FocusArea::UserSearchList {
+        s = s.fg(FOCUSED_COLOR);
+    }
+    let items: Vec<ListItem> = session.user_search.iter().map(|user| {
+        let mut spans = vec![];
+        if let Some(name) = &user.display_name {
+            spans.push(name.clone().bold());
+            spans.push(" ".into());
+        }
+        spans.push(user.user_id.to_string().dark_gray());
+        ListItem::new(Line::from(spans))
+    }).collect();
+    if app.user_search_state.selected().is_none() && !items.is_empty() {
+        app.user_search_state.select(Some(0));
+    }
+    let list = List::new(items)
+        .block(Block::bordered().title(" Users ").border_style(s))
+        .highlight_symbol("> ")
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    app.focus_area_positions.insert(FocusArea::UserSearchList, list_area);
+    frame.render_stateful_widget(list, list_area, &mut app.user_search_state);
+
+    let [cancel_area] = Layout::horizontal(vec![Constraint::Length(20)])
+        .flex(layout::Flex::Center)
+        .areas(buttons_area);
+    button_block(app, FocusArea::RoomDialogCancel, "Cancel", cancel_area, frame);
+}
+
+fn react_dialog(app: &mut App, session: &Session, event_id: &OwnedEventId, area: Rect, frame: &mut Frame) {
+    let popup = popup_area(area, 50, 20, frame);
+    let block = Block::bordered().title(" React ").padding(Padding::horizontal(1));
+    let inner = block.inner(popup);
+    frame.render_widget(block, popup);
+
+    let [input_area, list_area, buttons_area] = Layout::vertical(vec![
+        Constraint::Length(3),
+        Constraint::Min(3),
+        Constraint::Length(3)
+    ]).areas(inner);
+
+    one_line_input_block(
+        app,
+        FocusArea::ReactionSearchInput,
+        input_area,
+        Block::bordered().title(" Shortcode or emoji "),
+        frame
+    );
+
+    // 已经回应过的表情再次选择时会取消回应
+    let own: Vec<String> = app.selected_room.as_ref()
+        .and_then(|room_id| session.timelines.get(room_id))
+        .map(|timeline| timeline.reactions(event_id))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|group| group.own)
+        .map(|group| group.key)
+        .collect();
+
+    let results = emoji::search(&app.get_input_data(&FocusArea::ReactionSearchInput));
+    let items: Vec<ListItem> = results.iter().map(|(key, shortcode)| {
+        let mut spans = vec![key.clone().into(), " ".into(), shortcode.clone().dark_gray()];
+        if own.contains(key) {
+            spans.push(" ✓".green());
+        }
+        ListItem::new(Line::from(spans))
+    }).collect();
+    app.reaction_results = results.into_iter().map(|(key, _)| key).collect();
+    if app.reaction_list_state.selected().map_or(true, |index| index >= items.len()) {
+        app.reaction_list_state.select(if items.is_empty() { None } else { Some(0) });
+    }
+
+    let mut s = Style::default();
+    if app.current_focus == FocusArea::ReactionList {
+        s = s.fg(FOCUSED_COLOR);
+    }
+    let list = List::new(items)
+        .block(Block::bordered().title(" Emoji ").border_style(s))
+        .highlight_symbol("> ")
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    app.focus_area_positions.insert(FocusArea::ReactionList, list_area);
+    frame.render_stateful_widget(list, list_area, &mut app.reaction_list_state);
+
+    let [cancel_area] = Layout::horizontal(vec![Constraint::Length(20)])
+        .flex(layout::Flex::Center)
+        .areas(buttons_area);
+    button_block(app, FocusArea::RoomDialogCancel, "Cancel", cancel_area, frame);
+}
+
+fn hierarchy_dialog(app: &mut App, session: &Session, area: Rect, frame: &mut Frame) {
+    let popup = popup_area(area, 80, 20, frame);
+    let space_name = session.hierarchy.as_ref()
+        .and_then(|hierarchy| {
+            session.rooms.iter()
+                .find(|room| room.room_id == hierarchy.space_id)
+                .map(|room| room.name.clone())
+                .or_else(|| Some(hierarchy.space_id.to_string()))
+        })
+        .unwrap_or_default();
+    let block = Block::bordered().title(format!(" {} ", space_name)).padding(Padding::horizontal(1));
+    let inner = block.inner(popup);
+    frame.render_widget(block, popup);
+
+    let [list_area, buttons_area] = Layout::vertical(vec![
+        Constraint::Min(3),
+        Constraint::Length(3)
+    ]).areas(inner);
+
+    if session.hierarchy.as_ref().map_or(true, |hierarchy| hierarchy.loading) {
+        frame.render_widget(Paragraph::new("Loading...".italic()).centered(), list_area);
+    } else {
+        let mut s = Style::default();
+        if app.current_focus == FocusArea::HierarchyList {
+            s = s.fg(FOCUSED_COLOR);
+        }
+        let items: Vec<ListItem> = app.hierarchy.iter().map(|room| {
+            let mut spans = vec!["  ".repeat(room.depth).into()];
+            if room.is_space {
+                spans.push("▸ ".into());
+            }
+            spans.push(room.name.clone().bold());
+            spans.push(format!(" · {} members", room.members).dark_gray());
+            if room.joined {
+                spans.push(" (joined)".green());
+            }
+            if let Some(topic) = &room.topic {
+                spans.push(format!(" {}", topic.lines().next().unwrap_or_default()).dark_gray().italic());
+            }
+            ListItem::new(Line::from(spans))
+        }).collect();
+        if app.hierarchy_state.selected().is_none() && !items.is_empty() {
+            app.hierarchy_state.select(Some(0));
+        }
+        let list = List::new(items)
+            .block(Block::bordered().title(" Rooms (Enter to join) ").border_style(s))
+            .highlight_symbol("> ")
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        app.focus_area_positions.insert(FocusArea::HierarchyList, list_area);
+        frame.render_stateful_widget(list, list_area, &mut app.hierarchy_state);
+    }
+
+    let [close_area] = Layout::horizontal(vec![Constraint::Length(20)])
+        .flex(layout::Flex::Center)
+        .areas(buttons_area);
+    button_block(app, FocusArea::RoomDialogCancel, "Close", close_area, frame);
+}
+
+fn room_dialog(app: &mut App, dialog: &RoomDialog, session: &Session, area: Rect, frame: &mut Frame) {
+    let (title, text, buttons) = match dialog {
+        RoomDialog::Hierarchy => {
+            hierarchy_dialog(app, session, area, frame);
+            return;
+        },
+        RoomDialog::NewDm => {
+            new_dm_dialog(app, session, area, frame);
+            return;
+        },
+        RoomDialog::React(event_id) => {
+            react_dialog(app, session, event_id, area, frame);
+            return;
+        },
+        RoomDialog::Join => (
+            " Join Room ",
+            vec![Line::from("Enter a room ID, alias or matrix.to link")],
+            vec![(FocusArea::JoinBt, "Join"), (FocusArea::RoomDialogCancel, "Cancel")],
+        ),
+        RoomDialog::Leave(room_id) => {
+            let name = session.rooms.iter()
+                .find(|room| &room.room_id == room_id)
+                .map(|room| room.name.clone())
+                .unwrap_or_else(|| room_id.to_string());
+            (
+                " Leave Room ",
+                vec![
+                    Line::from(vec!["Leave ".into(), name.bold(), "?".into()]),
+                    Line::from("Forgetting also removes the room from your room history.".dark_gray()),
+                ],
+                vec![
+                    (FocusArea::LeaveBt, "Leave"),
+                    (FocusArea::LeaveForgetBt, "Leave & Forget"),
+                    (FocusArea::RoomDialogCancel, "Cancel"),
+                ],
+            )
+        },
+        RoomDialog::Logout => (
+            " Log Out ",
+            vec![
+                Line::from(vec!["Log out ".into(), session.user_id.to_string().bold(), "?".into()]),
+                Line::from("The local store will be deleted. Without key backup or another verified session you will lose access to encrypted messages.".red()),
+            ],
+            vec![(FocusArea::LogoutBt, "Log Out"), (FocusArea::RoomDialogCancel, "Cancel")],
+        ),
+        RoomDialog::LogoutOthers => (
+            " Log Out Other Sessions ",
+            vec![
+                Line::from(vec!["Log out all other sessions of ".into(), session.user_id.to_string().bold(), "?".into()]),
+                Line::from("Those sessions will lose their encryption keys unless they are backed up.".red()),
+            ],
+            vec![(FocusArea::LogoutOthersBt, "Log Out Others"), (FocusArea::RoomDialogCancel, "Cancel")],
+        ),
+        RoomDialog::Redact(_) => (
+            " Delete Message ",
+            vec![
+                Line::from("Delete this message for everyone in the room?"),
+                Line::from("You can give a reason, it will be visible to others.".dark_gray()),
+            ],
+            vec![(FocusArea::RedactBt, "Delete"), (FocusArea::RoomDialogCancel, "Cancel")],
+        ),
+    };
+
+    let input = match dialog {
+        RoomDialog::Join => Some((FocusArea::JoinInput, " Room ")),
+        RoomDialog::Redact(_) => Some((FocusArea::RedactReasonInput, " Reason (optional) ")),
+        _ => None,
+    };
+    let height = if input.is_some() { 11 } else { 8 };
+    let popup = popup_area(area, 70, height, frame);
+    let block = Block::bordered().title(title).padding(Padding::horizontal(1));
+    let inner = block.inner(popup);
+    frame.render_widget(block, popup);
+
+    let [text_area, input_area, buttons_area] = Layout::vertical(vec![
+        Constraint::Min(1),
+        Constraint::Length(if input.is_some() { 3 } else { 0 }),
+        Constraint::Length(3)
+    ]).areas(inner);
+
+    frame.render_widget(Paragraph::new(text).centered().wrap(Wrap { trim: true }), text_area);
+
+    if let Some((focus_area, title)) = input {
+        one_line_input_block(
+            app,
+            focus_area,
+            input_area,
+            Block::bordered().title(title),
+            frame
+        );
+    }
+
+    let button_areas = Layout::horizontal(vec![Constraint::Length(20); buttons.len()])
+        .flex(layout::Flex::Center)
+        .spacing(2)
+        .split(buttons_area);
+    for ((focus_area, label), rect) in buttons.into_iter().zip(button_areas.iter()) {
+        button_block(app, focus_area, label, *rect, frame);
+    }
+}
+
+fn uiaa_dialog(app: &mut App, dialog: &UiaaDialog, area: Rect, frame: &mut Frame) {
+    let popup = popup_area(area, 60, 14, frame);
+    let block = Block::bordered().title(format!(" {} ", dialog.title)).padding(Padding::horizontal(1));
+    let inner = block.inner(popup);
+    frame.render_widget(block, popup);
+
+    let [error_area, text_area, input_area, buttons_area] = Layout::vertical(vec![
+        Constraint::Length(1),
+        Constraint::Min(1),
+        Constraint::Length(3),
+        Constraint::Length(3)
+    ]).areas(inner);
+
+    if let Some(error) = &dialog.error {
+        frame.render_widget(Paragraph::new(error.clone().red()).centered(), error_area);
+    }
+
+    let confirm = match &dialog.stage {
+        UiaaStage::Password => {
+            frame.render_widget(
+                Paragraph::new("Confirm your identity by entering your password.").centered().wrap(Wrap { trim: true }),
+                text_area
+            );
+            one_line_input_block(
+                app,
+                FocusArea::UiaaPasswordInput,
+                input_area,
+                Block::bordered().title(" Password "),
+                frame
+            );
+            "Confirm"
+        },
+        UiaaStage::Sso(url) => {
+            let lines = vec![
+                Line::from("Confirm your identity with single sign-on in the browser, then continue:"),
+                Line::from(url.clone().underlined()),
+            ];
+            frame.render_widget(Paragraph::new(lines).centered().wrap(Wrap { trim: true }), text_area);
+            "Continue"
+        },
+        UiaaStage::Terms(policies) => {
+            let mut lines = vec![Line::from("Please review and accept the following terms:")];
+            for (name, url) in policies {
+                lines.push(Line::from(vec![name.clone().bold(), " ".into(), url.clone().underlined()]));
+            }
+            frame.render_widget(Paragraph::new(lines).centered().wrap(Wrap { trim: true }), text_area);
+            "Accept"
+        },
+    };
+
+    let [confirm_area, cancel_area] = Layout::horizontal(vec![Constraint::Length(20); 2])
+        .flex(layout::Flex::Center)
+        .spacing(2)
+        .areas(buttons_area);
+    button_block(app, FocusArea::UiaaConfirm, confirm, confirm_area, frame);
+    button_block(app, FocusArea::UiaaCancel, "Cancel", cancel_area, frame);
+}
+
+fn sso_dialog(app: &mut App, prompt: &SsoPrompt, area: Rect, frame: &mut Frame) {
+    let popup = popup_area(area, 70, 12, frame);
+    let block = Block::bordered().title(" Single Sign-On ").padding(Padding::horizontal(1));
+    let inner = block.inner(popup);
+    frame.render_widget(block, popup);
+
+    let [text_area, buttons_area] = Layout::vertical(vec![
+        Constraint::Min(1),
+        Constraint::Length(3)
+    ]).areas(inner);
+
+    // 地址可能很长，完整换行显示以便手动复制
+    let lines = vec![
+        Line::from("Log in in your browser. If it did not open, visit:"),
+        Line::from(""),
+        Line::from(prompt.url.clone().underlined()),
+    ];
+    frame.render_widget(Paragraph::new(lines).wrap(Wrap { trim: false }), text_area);
+
+    let [cancel_area] = Layout::horizontal(vec![Constraint::Length(20)])
+        .flex(layout::Flex::Center)
+        .areas(buttons_area);
+    button_block(app, FocusArea::SsoCancel, "Cancel", cancel_area, frame);
+}
+
+fn verification_dialog(app: &mut App, dialog: &VerificationDialog, area: Rect, frame: &mut Frame) {
+    let popup = popup_area(area, 60, 12, frame);
+    let block = Block::bordered().title(" Verify Session ").padding(Padding::horizontal(1));
+    let inner = block.inner(popup);
+    frame.render_widget(block, popup);
+
+    let [text_area, buttons_area] = Layout::vertical(vec![
+        Constraint::Min(1),
+        Constraint::Length(3)
+    ]).areas(inner);
+
+    let device = dialog.other_device.as_ref()
+        .map(|device| format!(" ({})", device))
+        .unwrap_or_default();
+
+    let (text, confirm, cancel) = match &dialog.stage {
+        VerificationStage::Incoming => (
+            format!("{}{} wants to verify this session.", dialog.other_user, device),
+            Some("Accept"),
+            Some("Cancel"),
+        ),
+        VerificationStage::Waiting => (
+            format!("Waiting for {}{}...", dialog.other_user, device),
+            None,
+            Some("Cancel"),
+        ),
+        VerificationStage::Emojis(emojis) => {
+            let [emoji_area, question_area] = Layout::vertical(vec![
+                Constraint::Length(3),
+                Constraint::Length(1)
+            ]).flex(layout::Flex::Center).areas(text_area);
+            let columns = Layout::horizontal(vec![Constraint::Ratio(1, 7); 7]).split(emoji_area);
+            for ((symbol, description), column) in emojis.iter().zip(columns.iter()) {
+                let emoji = Text::from(vec![
+                    Line::from(symbol.clone()),
+                    Line::from(""),
+                    Line::from(description.clone().dark_gray()),
+                ]).centered();
+                frame.render_widget(emoji, *column);
+            }
+            frame.render_widget(
+                Text::from("Do the emojis match those shown on the other device?").centered(),
+                question_area
+            );
+            (String::new(), Some("They match"), Some("They don't match"))
+        },
+        VerificationStage::Decimals(a, b, c) => (
+            format!("{} {} {}\n\nDo the numbers match those shown on the other device?", a, b, c),
+            Some("They match"),
+            Some("They don't match"),
+        ),
+        VerificationStage::Confirmed => (
+            "Waiting for the other device to confirm...".to_string(),
+            None,
+            Some("Cancel"),
+        ),
+        VerificationStage::Done => (
+            "This session has been verified.".to_string(),
+            Some("Close"),
+            None,
+        ),
+        VerificationStage::Cancelled(reason) => (
+            format!("Verification cancelled: {}", reason),
+            Some("Close"),
+            None,
+        ),
+    };
+
+    if !text.is_empty() {
+        let [text_area] = Layout::vertical([Constraint::Length(3)])
+            .flex(layout::Flex::Center)
+            .areas(text_area);
+        frame.render_widget(Paragraph::new(text).centered().wrap(Wrap { trim: true }), text_area);
+    }
+
+    let buttons: Vec<(FocusArea, &str)> = [
+        confirm.map(|label| (FocusArea::VerificationConfirm, label)),
+        cancel.map(|label| (FocusArea::VerificationCancel, label)),
+    ].into_iter().flatten().collect();
+
+    let button_areas = Layout::horizontal(vec![Constraint::Length(20); buttons.len()])
+        .flex(layout::Flex::Center)
+        .spacing(2)
+        .split(buttons_area);
+    for ((focus_area, label), rect) in buttons.into_iter().zip(button_areas.iter()) {
+        button_block(app, focus_area, label, *rect, frame);
+    }
+}
+
+// 一行快捷键提示，例如 Press <q> to exit, <i> to start editing.
+fn key_hints(hints: &[(&'static str, &'static str)]) -> Line<'static> {
+    let mut spans: Vec<Span> = vec!["Press ".into()];
+    for (index, (key, action)) in hints.iter().enumerate() {
+        let end = if index + 1 == hints.len() { "." } else { ", " };
+        spans.push(key.bold());
+        spans.push(format!(" to {}{}", action, end).into());
+    }
+    Line::from(spans)
+}
 
-    app.focus_area_positions.insert(area.clone(), rect);
+// 只显示当前界面可用的快捷键
+fn help_lines(app: &App) -> Vec<Line<'static>> {
+    if app.input_mode == InputMode::Editing {
+        return vec![key_hints(&[("<Esc>", "stop editing"), ("<Enter>", "send message or confirm")])];
+    }
 
-    frame.render_widget(inner_area, rect);
+    let mut common = vec![("<q>", "exit"), ("<i>", "start editing"), ("<Enter>", "select"), ("↑ ↓ ← →", "control focus")];
+    match app.current_screen {
+        CurrentScreen::Main if app.main_hotkeys() => vec![
+            key_hints(&[
+                ("<q>", "exit"),
+                ("<i>", "start editing"),
+                ("<Enter>", "select"),
+                ("<v>", "verify session"),
+                ("<a/n>", "switch/add account"),
+                ("<d>", "manage devices"),
+                ("<p>", "set passphrase"),
+                ("<o/O>", "log out this/other sessions"),
+            ]),
+            key_hints(&[
+                ("<j/c/l>", "join/create/leave room"),
+                ("<m>", "message someone"),
+                ("<f/h>", "filter/browse space"),
+                ("<r/g>", "reply/go to replied message"),
+                ("<e/x>", "edit/delete message"),
+                ("<+>", "react"),
+                ("↑ ↓ ← →", "control focus"),
+            ]),
+        ],
+        CurrentScreen::Main => {
+            // 验证弹窗只能通过按钮关闭
+            if app.show_uiaa || app.show_sso || app.selected_invite.is_some() || app.room_dialog.is_some() {
+                common.push(("<Esc>", "close"));
+            }
+            vec![key_hints(&common)]
+        },
+        CurrentScreen::Login => {
+            common.push(("<r>", "register"));
+            if app.active_account.is_some() {
+                common.push(("<Esc>", "go back"));
+            }
+            vec![key_hints(&common)]
+        },
+        CurrentScreen::Unlock => vec![key_hints(&common)],
+        CurrentScreen::Register | CurrentScreen::Vault | CurrentScreen::Devices | CurrentScreen::CreateRoom => {
+            common.push(("<Esc>", "go back"));
+            vec![key_hints(&common)]
+        },
+    }
 }
 
 pub fn ui(frame: &mut Frame, app: &mut App) {
@@ -147,21 +1719,116 @@ pub fn ui(frame: &mut Frame, app: &mut App) {
     // 获取 Matrix 数据
     let client = get_matrix_client();
     let mut is_add_info_error = false;
-    app.add_info = client.info_message;
+    app.add_info = client.info_message.clone();
     if !client.error_message.is_empty() {
-        app.add_info = client.error_message;
+        app.add_info = client.error_message.clone();
         is_add_info_error = true;
     }
 
-    if client.connected && app.current_screen != CurrentScreen::Main {
+    if matrix::take_show_login() {
+        app.current_screen = CurrentScreen::Login;
+        app.current_focus = FocusArea::ServerInput;
+    }
+
+    if matrix::take_show_main() {
+        app.current_screen = CurrentScreen::Main;
+        app.current_focus = FocusArea::RoomList;
+    }
+
+    // 切换账号后重置房间选择
+    if app.active_account != client.active {
+        app.active_account = client.active.clone();
+        app.select_room(None);
+        app.room_list_state = ListState::default();
+        app.dm_list_state = ListState::default();
+        app.space_filter = None;
+    }
+
+    let session = client.active_session().cloned();
+    app.spaces = session.iter()
+        .flat_map(|session| session.rooms.iter().filter(|room| room.is_space).map(|room| room.room_id.clone()))
+        .collect();
+    // 筛选的空间已经离开
+    if app.space_filter.as_ref().is_some_and(|space_id| !app.spaces.contains(space_id)) {
+        app.space_filter = None;
+    }
+    app.room_tree = session.as_ref()
+        .map(|session| spaces::build_tree(&session.rooms, app.space_filter.as_deref(), &app.collapsed_spaces))
+        .unwrap_or_default();
+    app.rooms = app.room_tree.iter().map(|row| row.room_id.clone()).collect();
+    app.hierarchy = session.as_ref()
+        .and_then(|session| session.hierarchy.as_ref())
+        .map(|hierarchy| hierarchy.rooms.clone())
+        .unwrap_or_default();
+    app.dms = session.iter()
+        .flat_map(|session| session.rooms.iter().filter(|room| room.direct).map(|room| room.room_id.clone()))
+        .collect();
+    app.user_search = session.iter()
+        .flat_map(|session| session.user_search.iter().map(|user| user.user_id.clone()))
+        .collect();
+    app.invites = session.iter()
+        .flat_map(|session| session.invites.iter().map(|invite| invite.room_id.clone()))
+        .collect();
+    app.devices = session.iter()
+        .flat_map(|session| session.devices.iter().map(|device| device.device_id.clone()))
+        .collect();
+
+    // 加入或创建房间后打开该房间
+    if let Some(room_id) = matrix::take_open_room() {
+        if let Some(index) = app.rooms.iter().position(|id| id == &room_id) {
+            app.room_list_state.select(Some(index));
+        }
+        if let Some(index) = app.dms.iter().position(|id| id == &room_id) {
+            app.dm_list_state.select(Some(index));
+        }
+        app.select_room(Some(room_id));
         app.current_screen = CurrentScreen::Main;
+        app.current_focus = FocusArea::Composer;
+    }
+
+    let timeline = app.selected_room.as_ref()
+        .and_then(|room_id| session.as_ref()?.timelines.get(room_id));
+    app.timeline_events = timeline.iter()
+        .flat_map(|timeline| timeline.items.iter())
+        .filter(|item| item.thread_root.is_none())
+        .filter_map(|item| item.event_id.clone())
+        .collect();
+    let open_thread = timeline.and_then(|timeline| timeline.open_thread.clone());
+    if app.open_thread != open_thread {
+        app.open_thread = open_thread;
+        app.thread_scroll = 0;
+    }
+    app.reply_parents = timeline.iter()
+        .flat_map(|timeline| timeline.items.iter())
+        .filter_map(|item| Some((item.event_id.clone()?, item.in_reply_to.clone()?)))
+        .collect();
+
+    // 跳转的消息加载完成后选中它
+    if let Some(event_id) = app.selected_room.as_ref().and_then(timeline::take_jump_target) {
+        app.selected_event = Some(event_id);
+        app.scroll_to_selection = true;
+        app.current_focus = FocusArea::Timeline;
+    }
+
+    let vault_locked = {
+        let saving = SAVING.lock().unwrap();
+        app.saved_accounts = saving.accounts.clone();
+        saving.is_locked()
+    };
+
+    // 保险库解锁后进入登录界面
+    if app.current_screen == CurrentScreen::Unlock && !vault_locked {
+        app.current_screen = CurrentScreen::Login;
+        app.current_focus = FocusArea::ServerInput;
     }
 
     // 布局
+    let help = help_lines(app);
+    let help_height = help.len() as u16;
     let layout = Layout::vertical(vec![
         Constraint::Min(1),
         Constraint::Length(1),
-        Constraint::Length(1)
+        Constraint::Length(help_height)
     ]).margin(1);
 
     let [main_area, info_area, help_area] = layout.areas(frame.size());
@@ -179,34 +1846,11 @@ pub fn ui(frame: &mut Frame, app: &mut App) {
         info_area
     );
 
-    let (msg, style) = match app.input_mode {
-        InputMode::Normal => (
-            vec![
-                "Press ".into(),
-                "<q>".bold(),
-                " to exit, ".into(),
-                "<i>".bold(),
-                " to start editing, ".into(),
-                "<Enter>".bold(),
-                " to select, ".into(),
-                "↑ ↓ ← →".bold(),
-                " to control focus.".into()
-            ],
-            Style::default().add_modifier(Modifier::RAPID_BLINK),
-        ),
-        InputMode::Editing => (
-            vec![
-                "Press ".into(),
-                "<Esc>".bold(),
-                " to stop editing, ".into(),
-                "<Enter>".bold(),
-                " to send message or confirm.".into(),
-            ],
-            Style::default(),
-        ),
+    let style = match app.input_mode {
+        InputMode::Normal => Style::default().add_modifier(Modifier::RAPID_BLINK),
+        InputMode::Editing => Style::default(),
     };
-
-    let help_text = Text::from(Line::from(msg)).patch_style(style).centered();
+    let help_text = Text::from(help).patch_style(style).centered();
     frame.render_widget(help_text, help_area);
 
     if client.loading {
@@ -216,69 +1860,159 @@ pub fn ui(frame: &mut Frame, app: &mut App) {
 
     match app.current_screen {
         CurrentScreen::Login => {
-            let layout = Layout::vertical(vec![
-                Constraint::Length(1),
-                Constraint::Length(3),
-                Constraint::Length(3),
-                Constraint::Length(3),
+            let main_area = if app.saved_accounts.is_empty() {
+                main_area
+            } else {
+                let [account_list_area, form_area] = Layout::horizontal(vec![
+                    Constraint::Percentage(30),
+                    Constraint::Percentage(70)
+                ]).areas(main_area);
+                account_list_block(app, &client, account_list_area, frame);
+                form_area
+            };
+
+            let server = app.get_input_data(&FocusArea::ServerInput);
+            app.login_flows = client.login_flows.clone().filter(|flows| flows.server == server);
+
+            login_form(app, main_area, frame);
+        },
+        CurrentScreen::Register => {
+            app.register_stage = client.registration.as_ref().map(|registration| registration.stage.clone());
+            register_form(app, client.registration.as_ref(), main_area, frame);
+        },
+        CurrentScreen::Devices => {
+            let Some(session) = session else {
+                frame.render_widget(Paragraph::new("No account".italic()).centered(), main_area);
+                return;
+            };
+            devices_screen(app, &session, main_area, frame);
+        },
+        CurrentScreen::CreateRoom => create_room_form(app, main_area, frame),
+        CurrentScreen::Unlock => vault_form(app, true, main_area, frame),
+        CurrentScreen::Vault => vault_form(app, false, main_area, frame),
+        CurrentScreen::Main => {
+            let Some(session) = session else {
+                frame.render_widget(Paragraph::new("No account".italic()).centered(), main_area);
+                return;
+            };
+
+            let layout = Layout::horizontal(vec![
+                Constraint::Percentage(25),
+                Constraint::Percentage(75)
+            ]);
+
+            let [room_list_area, room_area] = layout.areas(main_area);
+
+            // 有邀请时在房间列表上方单独列出
+            let room_list_area = if session.invites.is_empty() {
+                room_list_area
+            } else {
+                let invites_height = (session.invites.len() as u16 + 2).min(8);
+                let [invite_list_area, room_list_area] = Layout::vertical(vec![
+                    Constraint::Length(invites_height),
+                    Constraint::Min(3)
+                ]).areas(room_list_area);
+                invite_list_block(app, &session, invite_list_area, frame);
+                room_list_area
+            };
+
+            // 打开话题时在右侧显示话题侧边栏
+            let room_area = match app.open_thread.clone() {
+                Some(root) => {
+                    let [room_area, thread_area] = Layout::horizontal(vec![
+                        Constraint::Percentage(60),
+                        Constraint::Percentage(40)
+                    ]).areas(room_area);
+                    thread_block(app, &session, &root, thread_area, frame);
+                    room_area
+                },
+                None => room_area,
+            };
+
+            let [timeline_area, composer_area] = Layout::vertical(vec![
+                Constraint::Min(3),
                 Constraint::Length(3)
-            ])
-                .flex(layout::Flex::SpaceBetween)
-                .horizontal_margin(10)
-                .vertical_margin(1);
-
-            let [
-                text_area,
-                server_area,
-                username_area,
-                password_area,
-                login_button_area
-            ] = layout.areas(main_area);
-
-            let login_text = Text::from("Login to your matrix account".bold()).alignment(Alignment::Center);
-            frame.render_widget(login_text, text_area);
+            ]).areas(room_area);
 
-            one_line_input_block(
-                app,
-                FocusArea::ServerInput,
-                server_area,
-                Block::bordered().title(" Server Address "),
-                frame
-            );
+            // 私聊单独列在房间列表下方
+            let room_list_area = if app.dms.is_empty() {
+                room_list_area
+            } else {
+                let dms_height = (app.dms.len() as u16 + 2).min(room_list_area.height / 2);
+                let [room_list_area, dm_list_area] = Layout::vertical(vec![
+                    Constraint::Min(3),
+                    Constraint::Length(dms_height)
+                ]).areas(room_list_area);
+                dm_list_block(app, &session, dm_list_area, frame);
+                room_list_area
+            };
 
-            one_line_input_block(
-                app,
-                FocusArea::UsernameInput,
-                username_area,
-                Block::bordered().title(" Username "),
-                frame
-            );
+            room_list_block(app, &session, room_list_area, frame);
+            timeline_block(app, &session, timeline_area, frame);
 
-            one_line_input_block(
-                app,
-                FocusArea::PasswordInput,
-                password_area,
-                Block::bordered().title(" Password "),
-                frame
-            );
+            if let Some(room_id) = app.selected_room.clone() {
+                // 回复时标题显示回复的对象
+                let reply_sender = app.reply_to.as_ref()
+                    .and_then(|event_id| session.timelines.get(&room_id)?.find(event_id))
+                    .map(|item| item.sender.clone());
+                let title = match reply_sender {
+                    Some(sender) => format!(" Reply to {} (Esc to cancel) ", sender),
+                    None if app.editing.is_some() => " Edit message (Esc to cancel) ".to_string(),
+                    None => " Message ".to_string(),
+                };
+                one_line_input_block(
+                    app,
+                    FocusArea::Composer,
+                    composer_area,
+                    Block::bordered().title(title),
+                    frame
+                );
+            }
 
-            let login_bt = Paragraph::new("Login".bold())
-                .centered().block(Block::bordered())
-                .style(if app.current_focus == FocusArea::LoginBt {
-                    Style::default().fg(FOCUSED_COLOR)
-                } else {
-                    Style::default()
-                });
-            let [login_bt_layout] = Layout::horizontal(
-                vec![Constraint::Percentage(10)]
-            ).flex(layout::Flex::Center).areas(login_button_area);
-            app.focus_area_positions.insert(FocusArea::LoginBt, login_bt_layout);
-            frame.render_widget(login_bt, login_bt_layout);
-        },
-        CurrentScreen::Main => {
-            frame.render_widget(Text::from("Main Screen"), main_area);
+            // 弹窗显示时只有弹窗内的按钮可以获得焦点
+            let invite = app.selected_invite.as_ref()
+                .and_then(|room_id| session.invites.iter().find(|invite| &invite.room_id == room_id));
+            if let Some(invite) = invite {
+                app.focus_area_positions.clear();
+                invite_dialog(app, invite, main_area, frame);
+            } else {
+                // 邀请已经被处理
+                app.selected_invite = None;
+                if let Some(dialog) = app.room_dialog.clone() {
+                    app.focus_area_positions.clear();
+                    room_dialog(app, &dialog, &session, main_area, frame);
+                }
+            }
         },
     }
+
+    // 验证、认证弹窗可能出现在任何界面上
+    let verification = client.active_session().and_then(|session| session.verification.as_ref());
+    app.show_verification = verification.is_some();
+    if let Some(dialog) = verification {
+        app.focus_area_positions.clear();
+        verification_dialog(app, dialog, main_area, frame);
+    }
+
+    app.uiaa_stage = client.uiaa.as_ref().map(|dialog| dialog.stage.clone());
+    app.show_uiaa = client.uiaa.is_some();
+    if let Some(dialog) = &client.uiaa {
+        app.focus_area_positions.clear();
+        uiaa_dialog(app, dialog, main_area, frame);
+    }
+
+    app.show_sso = client.sso.is_some();
+    if let Some(prompt) = &client.sso {
+        app.focus_area_positions.clear();
+        sso_dialog(app, prompt, main_area, frame);
+    }
+
+    // 当前焦点不在界面上时回到左上角
+    if !app.focus_area_positions.contains_key(&app.current_focus) {
+        if let Some(focus_area) = get_top_left_focus_area(app) {
+            app.current_focus = focus_area;
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -291,15 +2025,94 @@ pub struct App {
     pub current_focus: FocusArea,
     pub add_info: String,
     pub focus_area_positions: HashMap<FocusArea, Rect>,
+    pub rooms: Vec<OwnedRoomId>,
+    pub room_list_state: ListState,
+    pub selected_room: Option<OwnedRoomId>,
+    // 时间线距离底部滚动的行数
+    pub timeline_scroll: u16,
+    pub timeline_max_scroll: u16,
+    // 时间线中选中的消息
+    pub selected_event: Option<OwnedEventId>,
+    // 选中的消息改变后需要滚动到它
+    pub scroll_to_selection: bool,
+    pub timeline_events: Vec<OwnedEventId>,
+    pub open_thread: Option<OwnedEventId>,
+    // 话题侧边栏距离底部滚动的行数
+    pub thread_scroll: u16,
+    pub thread_max_scroll: u16,
+    // 正在回复的消息
+    pub reply_to: Option<OwnedEventId>,
+    // 正在编辑的消息
+    pub editing: Option<OwnedEventId>,
+    // 时间线中的回复与其回复的消息
+    pub reply_parents: HashMap<OwnedEventId, OwnedEventId>,
+    pub active_account: Option<OwnedUserId>,
+    pub saved_accounts: Vec<Account>,
+    pub account_list_state: ListState,
+    // 最近一次查询登录方式的服务器地址
+    pub discovered_server: Option<String>,
+    pub login_flows: Option<LoginFlows>,
+    pub register_stage: Option<RegisterStage>,
+    pub show_verification: bool,
+    pub show_uiaa: bool,
+    pub show_sso: bool,
+    pub uiaa_stage: Option<UiaaStage>,
+    pub devices: Vec<OwnedDeviceId>,
+    pub invites: Vec<OwnedRoomId>,
+    pub invite_list_state: ListState,
+    // 正在处理的邀请
+    pub selected_invite: Option<OwnedRoomId>,
+    pub device_list_state: ListState,
+    room_dialog: Option<RoomDialog>,
+    pub new_room_public: bool,
+    pub new_room_encrypted: bool,
+    pub dms: Vec<OwnedRoomId>,
+    pub dm_list_state: ListState,
+    pub user_search: Vec<OwnedUserId>,
+    pub user_search_state: ListState,
+    // 回应弹窗中搜索到的表情
+    pub reaction_results: Vec<String>,
+    pub reaction_list_state: ListState,
+    // 房间列表按空间展开后的各行
+    pub room_tree: Vec<TreeRow>,
+    pub spaces: HashSet<OwnedRoomId>,
+    pub collapsed_spaces: HashSet<OwnedRoomId>,
+    pub space_filter: Option<OwnedRoomId>,
+    pub hierarchy: Vec<HierarchyRoom>,
+    pub hierarchy_state: ListState,
 }
 
 impl App {
     pub fn new() -> Self {
-        let mut app = Self::default();
-        app.should_exit = false;
+        let mut app = Self {
+            should_exit: false,
+            ..Default::default()
+        };
+        if SAVING.lock().unwrap().is_locked() {
+            app.current_screen = CurrentScreen::Unlock;
+            app.current_focus = FocusArea::VaultPassphraseInput;
+        }
         app
     }
 
+    fn discover_login_flows(&mut self) {
+        let server = self.get_input_data(&FocusArea::ServerInput);
+        if self.discovered_server.as_ref() != Some(&server) {
+            self.discovered_server = Some(server.clone());
+            tokio::spawn(matrix::discover_login_flows(server));
+        }
+    }
+
+    // 主界面上没有弹窗时才响应快捷键
+    fn main_hotkeys(&self) -> bool {
+        self.current_screen == CurrentScreen::Main
+            && self.room_dialog.is_none()
+            && self.selected_invite.is_none()
+            && !self.show_verification
+            && !self.show_uiaa
+            && !self.show_sso
+    }
+
     fn get_current_value(&self) -> &str {
         if let Some(t) = self.input_data.get(&self.current_focus) {
             t.as_str()
@@ -366,6 +2179,188 @@ impl App {
         }
     }
 
+    // 打开房间并加载历史消息，同时清除上一个房间中的选择
+    fn select_room(&mut self, room_id: Option<OwnedRoomId>) {
+        self.selected_room = room_id.clone();
+        self.selected_event = None;
+        self.reply_to = None;
+        self.cancel_editing();
+        self.timeline_scroll = 0;
+        if let Some(room_id) = room_id {
+            tokio::spawn(timeline::load_history(room_id));
+        }
+    }
+
+    // 跳转到选中消息所回复的消息，不在已加载范围内时向前加载
+    fn jump_to_reply_parent(&mut self) {
+        let Some(room_id) = self.selected_room.clone() else {
+            return;
+        };
+        let Some(parent) = self.selected_event.as_ref()
+            .and_then(|event_id| self.reply_parents.get(event_id))
+            .cloned() else {
+                return;
+            };
+
+        if self.timeline_events.contains(&parent) {
+            self.selected_event = Some(parent);
+            self.scroll_to_selection = true;
+        } else {
+            tokio::spawn(timeline::jump_to_event(room_id, parent));
+        }
+    }
+
+    fn move_timeline_selection(&mut self, direction: KeyCode) {
+        let index = self.selected_event.as_ref()
+            .and_then(|event_id| self.timeline_events.iter().position(|id| id == event_id));
+        match (direction, index) {
+            (KeyCode::Up, None) if !self.timeline_events.is_empty() => {
+                self.selected_event = self.timeline_events.last().cloned();
+            },
+            (KeyCode::Up, None | Some(0)) => {
+                // 已经选中最早的消息，加载更早的消息
+                if let Some(room_id) = self.selected_room.clone() {
+                    tokio::spawn(timeline::paginate_backwards(room_id));
+                }
+            },
+            (KeyCode::Up, Some(index)) => {
+                self.selected_event = self.timeline_events.get(index - 1).cloned();
+            },
+            (KeyCode::Down, Some(index)) => {
+                // 越过最新的消息后取消选中
+                self.selected_event = self.timeline_events.get(index + 1).cloned();
+                if self.selected_event.is_none() {
+                    self.timeline_scroll = 0;
+                }
+            },
+            _ => {}
+        }
+        self.scroll_to_selection = true;
+    }
+
+    // 按行滚动时间线，用于查看较长的消息
+    fn scroll_timeline(&mut self, direction: KeyCode) {
+        match direction {
+            KeyCode::Up | KeyCode::PageUp => {
+                if self.timeline_scroll >= self.timeline_max_scroll {
+                    // 已经滚动到顶部，加载更早的消息
+                    if let Some(room_id) = self.selected_room.clone() {
+                        tokio::spawn(timeline::paginate_backwards(room_id));
+                    }
+                } else {
+                    self.timeline_scroll += 1;
+                }
+            },
+            KeyCode::Down | KeyCode::PageDown => {
+                self.timeline_scroll = self.timeline_scroll.saturating_sub(1);
+            },
+            _ => {}
+        }
+    }
+
+    // 按行滚动话题侧边栏
+    fn scroll_thread(&mut self, direction: KeyCode) {
+        match direction {
+            KeyCode::Up | KeyCode::PageUp => {
+                self.thread_scroll = (self.thread_scroll + 1).min(self.thread_max_scroll);
+            },
+            KeyCode::Down | KeyCode::PageDown => {
+                self.thread_scroll = self.thread_scroll.saturating_sub(1);
+            },
+            _ => {}
+        }
+    }
+
+    fn send_message(&mut self) {
+        let body = self.get_input_data(&FocusArea::Composer);
+        if body.trim().is_empty() {
+            return;
+        }
+
+        if let Some(room_id) = self.selected_room.clone() {
+            self.clear_current_content();
+            if let Some(event_id) = self.editing.take() {
+                tokio::spawn(timeline::edit_message(room_id, event_id, body));
+                return;
+            }
+            self.selected_event = None;
+            self.timeline_scroll = 0;
+            let reply_to = self.reply_to.take();
+            tokio::spawn(timeline::send_message(room_id, body, None, reply_to));
+        }
+    }
+
+    // 在输入框中编辑选中的消息
+    fn start_editing(&mut self) {
+        let Some(room_id) = self.selected_room.clone() else {
+            return;
+        };
+        let Some(event_id) = self.selected_event.clone() else {
+            return;
+        };
+        let Some(body) = timeline::editable_body(&room_id, &event_id) else {
+            return;
+        };
+
+        self.reply_to = None;
+        self.editing = Some(event_id);
+        self.input_data.insert(FocusArea::Composer, body);
+        self.current_focus = FocusArea::Composer;
+        self.input_mode = InputMode::Editing;
+        self.move_cursor_rightest();
+    }
+
+    fn cancel_editing(&mut self) {
+        if self.editing.take().is_some() {
+            self.input_data.remove(&FocusArea::Composer);
+            self.reset_cursor();
+        }
+    }
+
+    fn send_thread_message(&mut self) {
+        let body = self.get_input_data(&FocusArea::ThreadComposer);
+        if body.trim().is_empty() {
+            return;
+        }
+
+        if let (Some(room_id), Some(root)) = (self.selected_room.clone(), self.open_thread.clone()) {
+            self.clear_current_content();
+            tokio::spawn(timeline::send_message(room_id, body, Some(root), None));
+        }
+    }
+
+    fn selected_device(&self) -> Option<OwnedDeviceId> {
+        self.device_list_state.selected().and_then(|index| self.devices.get(index)).cloned()
+    }
+
+    // 房间列表中选中的空间
+    fn highlighted_space(&self) -> Option<OwnedRoomId> {
+        self.room_list_state.selected()
+            .and_then(|index| self.rooms.get(index))
+            .filter(|room_id| self.spaces.contains(*room_id))
+            .cloned()
+    }
+
+    fn close_room_dialog(&mut self) {
+        if self.room_dialog.take() == Some(RoomDialog::Hierarchy) {
+            spaces::close_hierarchy();
+        }
+    }
+
+    fn respond_uiaa(&mut self, response: Option<UiaaResponse>) {
+        self.input_data.remove(&FocusArea::UiaaPasswordInput);
+        self.reset_cursor();
+        uiaa::respond(response);
+    }
+
+    fn leave_vault_screen(&mut self) {
+        if self.active_account.is_some() {
+            self.current_screen = CurrentScreen::Main;
+        } else {
+            self.current_screen = CurrentScreen::Login;
+        }
+    }
+
     fn click_focus(&mut self) {
         match self.current_focus {
             FocusArea::LoginBt => {
@@ -377,11 +2372,313 @@ impl App {
                     matrix::login(server, username, password).await;
                 });
             },
+            FocusArea::SsoBt(index) => {
+                let server = self.get_input_data(&FocusArea::ServerInput);
+                let identity_provider = self.login_flows.as_ref()
+                    .and_then(|flows| flows.identity_providers.get(index))
+                    .map(|(id, _)| id.clone());
+
+                tokio::spawn(matrix::login_sso(server, identity_provider));
+            },
+            FocusArea::TokenBt => {
+                let server = self.get_input_data(&FocusArea::ServerInput);
+                let token = self.get_input_data(&FocusArea::TokenInput);
+                if token.is_empty() {
+                    matrix::set_error_message("Missing blank");
+                    return;
+                }
+
+                tokio::spawn(async move {
+                    matrix::login_with_token(&server, &token).await;
+                });
+            },
+            FocusArea::AccountList => {
+                let Some(account) = self.account_list_state.selected()
+                    .and_then(|index| self.saved_accounts.get(index))
+                    .cloned() else {
+                        return;
+                    };
+
+                // 已登录的账号直接切换，否则恢复会话
+                let user_id = account.user_id.clone();
+                if let Ok(user_id) = OwnedUserId::try_from(user_id) {
+                    if matrix::activate_account(&user_id) {
+                        return;
+                    }
+                }
+                if account.has_session() {
+                    tokio::spawn(matrix::restore_session(account));
+                } else {
+                    self.input_data.insert(FocusArea::ServerInput, account.server);
+                    self.input_data.insert(FocusArea::UsernameInput, account.username);
+                    self.current_focus = FocusArea::PasswordInput;
+                    self.discover_login_flows();
+                }
+            },
+            FocusArea::RegisterBt => {
+                let server = self.get_input_data(&FocusArea::ServerInput);
+                let username = self.get_input_data(&FocusArea::UsernameInput);
+                let password = self.get_input_data(&FocusArea::PasswordInput);
+
+                tokio::spawn(register::register(server, username, password));
+            },
+            FocusArea::RegisterConfirm => match self.register_stage {
+                Some(RegisterStage::Terms(_)) => {
+                    tokio::spawn(register::accept_terms());
+                },
+                Some(RegisterStage::RegistrationToken) => {
+                    let token = self.get_input_data(&FocusArea::RegistrationTokenInput);
+                    tokio::spawn(register::submit_registration_token(token));
+                },
+                Some(RegisterStage::Email) => {
+                    let email = self.get_input_data(&FocusArea::EmailInput);
+                    tokio::spawn(register::request_email(email));
+                },
+                Some(RegisterStage::EmailSent(_)) => {
+                    tokio::spawn(register::confirm_email());
+                },
+                _ => {}
+            },
+            FocusArea::UiaaConfirm => {
+                let response = match self.uiaa_stage {
+                    Some(UiaaStage::Password) => {
+                        UiaaResponse::Password(self.get_input_data(&FocusArea::UiaaPasswordInput))
+                    },
+                    _ => UiaaResponse::Continue,
+                };
+                self.respond_uiaa(Some(response));
+            },
+            FocusArea::UiaaCancel => {
+                self.respond_uiaa(None);
+            },
+            FocusArea::SsoCancel => {
+                matrix::cancel_sso();
+            },
+            FocusArea::DeviceRenameBt => {
+                let Some(device_id) = self.selected_device() else {
+                    return;
+                };
+                let name = self.get_input_data(&FocusArea::DeviceNameInput);
+                self.input_data.remove(&FocusArea::DeviceNameInput);
+                tokio::spawn(devices::rename_device(device_id, name));
+            },
+            FocusArea::DeviceDeleteBt => {
+                let Some(device_id) = self.selected_device() else {
+                    return;
+                };
+                tokio::spawn(devices::delete_device(device_id));
+            },
+            FocusArea::DeviceVerifyBt => {
+                let Some(device_id) = self.selected_device() else {
+                    return;
+                };
+                // 验证弹窗显示在主界面
+                self.current_screen = CurrentScreen::Main;
+                tokio::spawn(verification::start_device_verification(device_id));
+            },
+            FocusArea::RegisterCancel => {
+                register::cancel();
+            },
+            FocusArea::VaultBt => {
+                let passphrase = self.get_input_data(&FocusArea::VaultPassphraseInput);
+                if passphrase.is_empty() {
+                    matrix::set_error_message("Missing blank");
+                    return;
+                }
+                self.input_data.remove(&FocusArea::VaultPassphraseInput);
+                self.reset_cursor();
+
+                if self.current_screen == CurrentScreen::Unlock {
+                    tokio::spawn(unlock_vault(passphrase));
+                } else {
+                    self.leave_vault_screen();
+                    tokio::spawn(enable_vault(passphrase));
+                }
+            },
+            FocusArea::VerificationConfirm => {
+                tokio::spawn(verification::confirm());
+            },
+            FocusArea::VerificationCancel => {
+                tokio::spawn(verification::cancel());
+            },
+            FocusArea::InviteList => {
+                self.selected_invite = self.invite_list_state.selected()
+                    .and_then(|index| self.invites.get(index))
+                    .cloned();
+            },
+            FocusArea::InviteAccept => {
+                if let Some(room_id) = self.selected_invite.take() {
+                    tokio::spawn(rooms::accept_invite(room_id));
+                }
+            },
+            FocusArea::InviteDecline => {
+                if let Some(room_id) = self.selected_invite.take() {
+                    tokio::spawn(rooms::decline_invite(room_id, false));
+                }
+            },
+            FocusArea::InviteIgnore => {
+                if let Some(room_id) = self.selected_invite.take() {
+                    tokio::spawn(rooms::decline_invite(room_id, true));
+                }
+            },
+            FocusArea::JoinBt => {
+                let target = self.get_input_data(&FocusArea::JoinInput);
+                if target.trim().is_empty() {
+                    matrix::set_error_message("Missing blank");
+                    return;
+                }
+                self.input_data.remove(&FocusArea::JoinInput);
+                self.room_dialog = None;
+                tokio::spawn(rooms::join_room(target));
+            },
+            FocusArea::RoomPublicToggle => {
+                self.new_room_public = !self.new_room_public;
+            },
+            FocusArea::RoomEncryptedToggle => {
+                self.new_room_encrypted = !self.new_room_encrypted;
+            },
+            FocusArea::CreateRoomBt => {
+                let new_room = NewRoom {
+                    name: self.get_input_data(&FocusArea::RoomNameInput),
+                    topic: self.get_input_data(&FocusArea::RoomTopicInput),
+                    public: self.new_room_public,
+                    encrypted: self.new_room_encrypted,
+                    invitees: self.get_input_data(&FocusArea::RoomInviteesInput),
+                };
+                if new_room.name.trim().is_empty() {
+                    matrix::set_error_message("Missing blank");
+                    return;
+                }
+                for area in [FocusArea::RoomNameInput, FocusArea::RoomTopicInput, FocusArea::RoomInviteesInput] {
+                    self.input_data.remove(&area);
+                }
+                tokio::spawn(rooms::create_room(new_room));
+            },
+            FocusArea::LeaveBt | FocusArea::LeaveForgetBt => {
+                let forget = self.current_focus == FocusArea::LeaveForgetBt;
+                if let Some(RoomDialog::Leave(room_id)) = self.room_dialog.take() {
+                    if self.selected_room.as_ref() == Some(&room_id) {
+                        self.select_room(None);
+                    }
+                    tokio::spawn(rooms::leave_room(room_id, forget));
+                }
+            },
+            FocusArea::LogoutBt => {
+                if let Some(RoomDialog::Logout) = self.room_dialog.take() {
+                    tokio::spawn(matrix::logout());
+                }
+            },
+            FocusArea::LogoutOthersBt => {
+                if let Some(RoomDialog::LogoutOthers) = self.room_dialog.take() {
+                    tokio::spawn(matrix::logout_other_sessions());
+                }
+            },
+            FocusArea::RedactBt => {
+                let Some(room_id) = self.selected_room.clone() else {
+                    return;
+                };
+                if let Some(RoomDialog::Redact(event_id)) = self.room_dialog.take() {
+                    let reason = self.input_data.remove(&FocusArea::RedactReasonInput)
+                        .filter(|reason| !reason.trim().is_empty());
+                    if self.editing.as_ref() == Some(&event_id) {
+                        self.cancel_editing();
+                    }
+                    tokio::spawn(timeline::redact_message(room_id, event_id, reason));
+                }
+            },
+            FocusArea::ReactionList => {
+                let Some(room_id) = self.selected_room.clone() else {
+                    return;
+                };
+                let Some(key) = self.reaction_list_state.selected()
+                    .and_then(|index| self.reaction_results.get(index))
+                    .cloned() else {
+                        return;
+                    };
+                if let Some(RoomDialog::React(event_id)) = self.room_dialog.take() {
+                    self.input_data.remove(&FocusArea::ReactionSearchInput);
+                    tokio::spawn(timeline::toggle_reaction(room_id, event_id, key));
+                }
+            },
+            FocusArea::UserSearchBt => {
+                let term = self.get_input_data(&FocusArea::UserSearchInput);
+                self.user_search_state = ListState::default();
+                tokio::spawn(rooms::search_users(term));
+            },
+            FocusArea::UserSearchList => {
+                let Some(user_id) = self.user_search_state.selected()
+                    .and_then(|index| self.user_search.get(index))
+                    .cloned() else {
+                        return;
+                    };
+                self.input_data.remove(&FocusArea::UserSearchInput);
+                self.room_dialog = None;
+                tokio::spawn(rooms::start_dm(user_id));
+            },
+            FocusArea::RoomDialogCancel => {
+                self.close_room_dialog();
+            },
+            FocusArea::HierarchyList => {
+                let Some(room) = self.hierarchy_state.selected()
+                    .and_then(|index| self.hierarchy.get(index))
+                    .cloned() else {
+                        return;
+                    };
+                if !room.joined {
+                    // 加入空间后留在列表中继续浏览
+                    if !room.is_space {
+                        self.close_room_dialog();
+                    }
+                    tokio::spawn(spaces::join_hierarchy_room(room));
+                } else if !room.is_space {
+                    self.close_room_dialog();
+                    self.select_room(Some(room.room_id));
+                }
+            },
+            FocusArea::Timeline => {
+                // 在侧边栏中打开选中消息的话题
+                if let (Some(room_id), Some(event_id)) = (self.selected_room.clone(), self.selected_event.clone()) {
+                    timeline::open_thread(room_id.clone(), event_id.clone());
+                    self.current_focus = FocusArea::ThreadComposer;
+                    tokio::spawn(timeline::load_thread(room_id, event_id));
+                }
+            },
+            FocusArea::RoomList if self.highlighted_space().is_some() => {
+                if let Some(space_id) = self.highlighted_space() {
+                    if !self.collapsed_spaces.remove(&space_id) {
+                        self.collapsed_spaces.insert(space_id);
+                    }
+                }
+            },
+            FocusArea::RoomList | FocusArea::DmList => {
+                let (rooms, state) = if self.current_focus == FocusArea::DmList {
+                    (&self.dms, &self.dm_list_state)
+                } else {
+                    (&self.rooms, &self.room_list_state)
+                };
+                if let Some(room_id) = state.selected().and_then(|index| rooms.get(index)).cloned() {
+                    self.select_room(Some(room_id));
+                }
+            },
             _ => {}
         }
     }
 }
 
+fn move_list_selection(state: &mut ListState, len: usize, direction: KeyCode) {
+    if len == 0 {
+        return;
+    }
+
+    let current = state.selected().unwrap_or(0);
+    let next = match direction {
+        KeyCode::Up => current.saturating_sub(1),
+        KeyCode::Down => (current + 1).min(len - 1),
+        _ => current
+    };
+    state.select(Some(next));
+}
+
 pub fn loading_ui(frame: &mut Frame, area: Rect) {
     let centered_layout = Layout::vertical(
         [Constraint::Length(1)]
@@ -394,7 +2691,55 @@ pub fn loading_ui(frame: &mut Frame, area: Rect) {
 }
 
 pub async fn preload_app(saving: Saving) {
-    if !saving.token.is_empty() {
-        matrix::login_with_token(&saving.server, &saving.token).await;
+    // 默认使用第一个保存的账号
+    let first = saving.accounts.iter()
+        .filter(|account| account.has_session())
+        .find_map(|account| UserId::parse(&account.user_id).ok());
+    if let Some(user_id) = first {
+        matrix::choose_account(&user_id);
+    }
+
+    for account in saving.accounts {
+        if account.has_session() {
+            tokio::spawn(matrix::restore_session(account));
+        }
+    }
+}
+
+// 解锁保险库后再恢复保存的会话，派生密钥较慢，在阻塞线程中进行
+async fn unlock_vault(passphrase: String) {
+    matrix::set_error_message("");
+    matrix::set_loading(true);
+
+    let vault = SAVING.lock().unwrap().vault.clone();
+    let key = match vault {
+        Some(vault) => tokio::task::spawn_blocking(move || vault.unlock(&passphrase)).await.ok().flatten(),
+        None => None,
+    };
+    matrix::set_loading(false);
+
+    // 派生密钥期间存档可能已经被修改，在锁内解密
+    let saving = {
+        let mut saving = SAVING.lock().unwrap();
+        let unlocked = key.is_some_and(|key| saving.unlock(key));
+        unlocked.then(|| saving.clone())
+    };
+    let Some(saving) = saving else {
+        matrix::set_error_message("Wrong passphrase");
+        return;
+    };
+
+    preload_app(saving).await;
+}
+
+async fn enable_vault(passphrase: String) {
+    matrix::set_error_message("");
+    matrix::set_loading(true);
+
+    let vault = tokio::task::spawn_blocking(move || VaultHeader::create(&passphrase)).await;
+    if let Ok((header, key)) = vault {
+        SAVING.lock().unwrap().enable_vault(header, key);
     }
+
+    matrix::set_loading(false);
 }
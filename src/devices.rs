@@ -0,0 +1,102 @@
+use chrono::{DateTime, Local};
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedDeviceId};
+
+use crate::matrix::{delete_devices, get_client, set_error_message, with_session};
+
+#[derive(Debug, Clone)]
+pub struct DeviceItem {
+	pub device_id: OwnedDeviceId,
+	pub display_name: Option<String>,
+	pub last_seen_ip: Option<String>,
+	pub last_seen_ts: Option<MilliSecondsSinceUnixEpoch>,
+	pub verified: bool,
+	// 是否是当前正在使用的设备
+	pub current: bool,
+}
+
+impl DeviceItem {
+	pub fn last_seen(&self) -> String {
+		let Some(timestamp) = self.last_seen_ts else {
+			return String::new();
+		};
+		match DateTime::from_timestamp_millis(i64::from(timestamp.get())) {
+			Some(time) => time.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string(),
+			None => String::new(),
+		}
+	}
+}
+
+// 重新获取当前账号的设备列表和每个设备的验证状态
+pub async fn refresh_devices() {
+	let Some(client) = get_client() else {
+		return;
+	};
+	let Some(user_id) = client.user_id().map(|user_id| user_id.to_owned()) else {
+		return;
+	};
+	let current = client.device_id().map(|device_id| device_id.to_owned());
+
+	let devices = match client.devices().await {
+		Ok(response) => response.devices,
+		Err(e) => {
+			set_error_message(format!("Failed to get devices: {}", e));
+			return;
+		}
+	};
+
+	let mut items = Vec::new();
+	for device in devices {
+		let verified = match client.encryption().get_device(&user_id, &device.device_id).await {
+			Ok(Some(device)) => device.is_verified(),
+			_ => false,
+		};
+		items.push(DeviceItem {
+			current: current.as_ref() == Some(&device.device_id),
+			device_id: device.device_id,
+			display_name: device.display_name,
+			last_seen_ip: device.last_seen_ip,
+			last_seen_ts: device.last_seen_ts,
+			verified,
+		});
+	}
+	// 当前设备排在最前面，其余按最近使用时间排序
+	items.sort_by(|a, b| b.current.cmp(&a.current).then(b.last_seen_ts.cmp(&a.last_seen_ts)));
+
+	with_session(&user_id, |session| session.devices = items);
+}
+
+pub async fn rename_device(device_id: OwnedDeviceId, name: String) {
+	let Some(client) = get_client() else {
+		return;
+	};
+	if name.is_empty() {
+		set_error_message("Missing blank");
+		return;
+	}
+
+	set_error_message("");
+	if let Err(e) = client.rename_device(&device_id, &name).await {
+		set_error_message(format!("Failed to rename device: {}", e));
+		return;
+	}
+
+	refresh_devices().await;
+}
+
+pub async fn delete_device(device_id: OwnedDeviceId) {
+	let Some(client) = get_client() else {
+		return;
+	};
+	if client.device_id() == Some(&device_id) {
+		set_error_message("Log out to remove the current session");
+		return;
+	}
+
+	set_error_message("");
+	if let Err(e) = delete_devices(&client, &[device_id], "Delete device").await {
+		set_error_message(format!("Failed to delete device: {}", e));
+		return;
+	}
+
+	refresh_devices().await;
+}
@@ -0,0 +1,174 @@
+// 回应时可以选择的常用表情及其短代码
+const EMOJIS: &[(&str, &str)] = &[
+	("👍", "thumbsup"),
+	("👎", "thumbsdown"),
+	("❤️", "heart"),
+	("😂", "joy"),
+	("😄", "smile"),
+	("😃", "smiley"),
+	("😀", "grinning"),
+	("😁", "grin"),
+	("😆", "laughing"),
+	("😅", "sweat_smile"),
+	("🤣", "rofl"),
+	("🙂", "slightly_smiling_face"),
+	("😉", "wink"),
+	("😊", "blush"),
+	("😇", "innocent"),
+	("😍", "heart_eyes"),
+	("🥰", "smiling_face_with_hearts"),
+	("😘", "kissing_heart"),
+	("😋", "yum"),
+	("😛", "stuck_out_tongue"),
+	("😜", "stuck_out_tongue_winking_eye"),
+	("🤪", "zany_face"),
+	("🤔", "thinking"),
+	("🤨", "raised_eyebrow"),
+	("😐", "neutral_face"),
+	("😑", "expressionless"),
+	("😶", "no_mouth"),
+	("🙄", "roll_eyes"),
+	("😏", "smirk"),
+	("😬", "grimacing"),
+	("😌", "relieved"),
+	("😔", "pensive"),
+	("😴", "sleeping"),
+	("😷", "mask"),
+	("🤒", "face_with_thermometer"),
+	("🤯", "exploding_head"),
+	("🥳", "partying_face"),
+	("😎", "sunglasses"),
+	("🤓", "nerd_face"),
+	("😕", "confused"),
+	("😟", "worried"),
+	("🙁", "slightly_frowning_face"),
+	("😮", "open_mouth"),
+	("😲", "astonished"),
+	("😳", "flushed"),
+	("🥺", "pleading_face"),
+	("😢", "cry"),
+	("😭", "sob"),
+	("😱", "scream"),
+	("😤", "triumph"),
+	("😡", "rage"),
+	("😠", "angry"),
+	("🤬", "cursing_face"),
+	("💀", "skull"),
+	("💩", "poop"),
+	("🤡", "clown_face"),
+	("👻", "ghost"),
+	("👽", "alien"),
+	("🤖", "robot"),
+	("🙈", "see_no_evil"),
+	("👋", "wave"),
+	("👌", "ok_hand"),
+	("✌️", "v"),
+	("🤞", "crossed_fingers"),
+	("🤝", "handshake"),
+	("👏", "clap"),
+	("🙌", "raised_hands"),
+	("🙏", "pray"),
+	("💪", "muscle"),
+	("👀", "eyes"),
+	("🧡", "orange_heart"),
+	("💛", "yellow_heart"),
+	("💚", "green_heart"),
+	("💙", "blue_heart"),
+	("💜", "purple_heart"),
+	("🖤", "black_heart"),
+	("💔", "broken_heart"),
+	("💯", "100"),
+	("🔥", "fire"),
+	("✨", "sparkles"),
+	("⭐", "star"),
+	("🌟", "star2"),
+	("⚡", "zap"),
+	("🎉", "tada"),
+	("🎊", "confetti_ball"),
+	("🎁", "gift"),
+	("🏆", "trophy"),
+	("🚀", "rocket"),
+	("☕", "coffee"),
+	("🍺", "beer"),
+	("🍻", "beers"),
+	("🍕", "pizza"),
+	("🍰", "cake"),
+	("🐛", "bug"),
+	("🐱", "cat"),
+	("🐶", "dog"),
+	("🦀", "crab"),
+	("🌈", "rainbow"),
+	("☀️", "sunny"),
+	("✅", "white_check_mark"),
+	("✔️", "heavy_check_mark"),
+	("❌", "x"),
+	("❗", "exclamation"),
+	("❓", "question"),
+	("⚠️", "warning"),
+	("🚫", "no_entry_sign"),
+	("➕", "heavy_plus_sign"),
+	("➖", "heavy_minus_sign"),
+	("💡", "bulb"),
+	("📌", "pushpin"),
+	("🔗", "link"),
+	("🆗", "ok"),
+	("🆒", "cool"),
+];
+
+// 按短代码搜索表情，输入的内容不是短代码时也可以直接作为回应
+pub fn search(term: &str) -> Vec<(String, String)> {
+	let term = term.trim();
+	let code = term.trim_matches(':').to_lowercase();
+
+	let mut results: Vec<(String, String)> = Vec::new();
+	for (emoji, shortcode) in EMOJIS {
+		if code.is_empty() || shortcode.contains(code.as_str()) || *emoji == term {
+			results.push((emoji.to_string(), format!(":{}:", shortcode)));
+		}
+	}
+
+	// 直接输入的表情或文字
+	let custom = !term.is_empty() && !term.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+	if custom && !results.iter().any(|(key, _)| key == term) {
+		results.insert(0, (term.to_string(), String::new()));
+	}
+	results
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn lists_all_emojis_for_empty_term() {
+		assert_eq!(search("").len(), EMOJIS.len());
+		assert_eq!(search("  ").len(), EMOJIS.len());
+	}
+
+	#[test]
+	fn searches_shortcodes() {
+		let results = search(":thumbs");
+		assert_eq!(results, vec![
+			("👍".to_string(), ":thumbsup:".to_string()),
+			("👎".to_string(), ":thumbsdown:".to_string()),
+		]);
+		assert_eq!(search("CRAB"), vec![("🦀".to_string(), ":crab:".to_string())]);
+	}
+
+	#[test]
+	fn finds_emoji_by_itself() {
+		assert_eq!(search("🦀"), vec![("🦀".to_string(), ":crab:".to_string())]);
+	}
+
+	#[test]
+	fn offers_custom_reactions() {
+		let results = search("🦄");
+		assert_eq!(results, vec![("🦄".to_string(), String::new())]);
+		assert_eq!(search("+1")[0], ("+1".to_string(), String::new()));
+	}
+
+	#[test]
+	fn ignores_unknown_shortcodes() {
+		assert!(search(":no_such_emoji:").is_empty());
+	}
+}
@@ -1,3 +1,5 @@
+#![recursion_limit = "256"]
+
 use std::io::{self, stdout};
 
 use app::{handle_events, loading_ui, preload_app, ui, App};
@@ -13,9 +15,19 @@ use ratatui::{
 use save::SAVING;
 
 mod app;
+mod devices;
+mod emoji;
 mod pos;
 mod matrix;
+mod register;
+mod rooms;
 mod save;
+mod spaces;
+mod timeline;
+mod uiaa;
+mod vault;
+mod verification;
+mod widgets;
 
 fn startup(frame: &mut Frame) {
     let layout = Layout::vertical(vec![Constraint::Percentage(100)]);
@@ -33,14 +45,16 @@ async fn main() -> io::Result<()> {
     ctrlc::set_handler(|| {}).expect("Failed to set Ctrl-C handler");
 
     // 启动界面
-    terminal.draw(|f| startup(f))?;
+    terminal.draw(startup)?;
 
     // 创建 app 实例
     let mut app = App::new();
 
-    // 预加载
+    // 预加载，启用保险库时等待输入口令后再加载
     let saving = SAVING.lock().unwrap().clone();
-    tokio::spawn(preload_app(saving));
+    if !saving.is_locked() {
+        tokio::spawn(preload_app(saving));
+    }
 
     // 主循环
     while !app.should_exit {
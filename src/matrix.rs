@@ -1,14 +1,23 @@
-use std::sync::Mutex;
+use std::{collections::HashMap, fmt::Display, fs, net::Ipv4Addr, process::{Command, Stdio}, sync::{atomic::{AtomicU32, Ordering}, Arc, Mutex}, time::Duration};
 
-use matrix_sdk::{config::SyncSettings, ruma::events::{room::message::SyncRoomMessageEvent, MessageLikeEventType}, Client, ServerName};
+use matrix_sdk::{config::SyncSettings, matrix_auth::{MatrixSession, MatrixSessionTokens}, ruma::{api::client::{error::ErrorKind, session::{get_login_types::v3::LoginType, login::{self, v3::LoginInfo}}, uiaa::UserIdentifier}, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UserId}, BaseRoom, Client, LoopCtrl, Room, ServerName, SessionMeta};
 use lazy_static::lazy_static;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener, task::AbortHandle};
+use url::Url;
 
-use crate::save::{BUNDLE_ID, SAVING};
+use crate::{devices::DeviceItem, register::Registration, rooms::{handle_invite, UserItem}, save::{get_store_name, get_store_path, Account, SAVING}, spaces::{space_children, space_parents, Hierarchy}, vault::random_passphrase, uiaa::{with_uiaa, UiaaDialog, UiaaError}, timeline::{handle_sync_encrypted, handle_sync_message, handle_sync_reaction, handle_sync_redaction, retry_decryption, Timeline}, verification::{handle_verification_request, handle_verification_start, VerificationDialog}};
 
 lazy_static! {
     pub static ref MATRIX_CLIENT: Mutex<MatrixClient> = Mutex::new(MatrixClient::new());
 }
 
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// 连续失败超过该次数后视为离线
+const OFFLINE_FAILURES: u32 = 5;
+// 等待浏览器完成 SSO 登录的时间
+const SSO_TIMEOUT: Duration = Duration::from_secs(300);
+
 pub async fn login(server: String, username: String, password: String) -> Option<Client> {
 	set_error_message("");
 	set_loading(true);
@@ -32,46 +41,90 @@ pub async fn login(server: String, username: String, password: String) -> Option
 	let client = match Client::builder().server_name(&server_name).build().await {
 		Ok(client) => client,
 		Err(e) => {
-			set_error_message(format!("Failed to connect to server: {}", e.to_string()));
+			set_error_message(format!("Failed to connect to server: {}", e));
 			set_loading(false);
 			return None;
 		}
 	};
 
-	let device_id = client.device_id()?;
-	set_info_message(format!("Registering device: {:?}", device_id));
+	set_info_message("Logging in");
+	let identifier = UserIdentifier::UserIdOrLocalpart(username.clone());
+	let request = login::v3::Request::new(LoginInfo::Password(login::v3::Password::new(identifier, password)));
+	let response = match client.send(request, None).await {
+		Ok(response) => response,
+		Err(e) => {
+			set_error_message(format!("Failed to login: {}", e));
+			set_loading(false);
+			return None;
+		}
+	};
+
+	let Some((client, store, store_passphrase)) = open_store(client.homeserver(), (&response).into()).await else {
+		set_loading(false);
+		return None;
+	};
+
+	let Some(device_id) = client.device_id() else {
+		set_loading(false);
+		set_error_message("Failed to login: missing device ID");
+		return None;
+	};
+	set_info_message(format!("Registering device: {}", device_id));
 	let _ = client.rename_device(
 		device_id,
 		"Matrix Tui"
 	).await;
 
-	set_info_message("Logging in");
-	match client.matrix_auth()
-		.login_username(username.clone(), &password)
-		.send().await {
-			Ok(_) => {},
+	replace_session(&client, &server, &username, &store, store_passphrase);
+
+	Some(client)
+}
+
+// 登录成功后再创建本地存储，存储按用户和设备区分，登录失败或重新登录都不会影响已有的存储
+pub async fn open_store(homeserver: Url, session: MatrixSession) -> Option<(Client, String, Option<String>)> {
+	let store = get_store_name(session.meta.user_id.as_str(), session.meta.device_id.as_str());
+	let store_passphrase = new_store_passphrase();
+
+	let client = match Client::builder()
+		.homeserver_url(homeserver)
+		.sqlite_store(get_store_path(&store), store_passphrase.as_deref())
+		.build().await {
+			Ok(client) => client,
 			Err(e) => {
-				set_error_message(format!("Failed to login: {}", e.to_string()));
-				set_loading(false);
+				set_error_message(format!("Failed to open local store: {}", e));
 				return None;
 			}
 		};
 
-	if let Some(token) = client.access_token() {
-		let mut saving = SAVING.lock().unwrap();
-		saving.token = token;
-		saving.username = username.clone();
-		saving.server = server.clone();
-		saving.save();
+	if let Err(e) = client.restore_session(session).await {
+		set_error_message(format!("Failed to restore session: {}", e));
+		return None;
 	}
 
-	connect(&client).await;
+	Some((client, store, store_passphrase))
+}
 
-	set_loading(false);
+// 保存新的会话并开始同步，同一账号之前的会话停止后再删除它的本地存储
+pub fn replace_session(client: &Client, server: &str, username: &str, store: &str, store_passphrase: Option<String>) {
+	let old_store = client.user_id().and_then(|user_id| {
+		SAVING.lock().unwrap().accounts.iter()
+			.find(|account| account.user_id == user_id.as_str())
+			.map(|account| account.store.clone())
+	});
+	save_session(client, server, username, store, store_passphrase);
+	if let Some(user_id) = client.user_id() {
+		choose_account(user_id);
+	}
 
-	Some(client)
+	// 首次同步完成后由同步任务结束加载状态
+	connect(client);
+
+	if let Some(old_store) = old_store.filter(|old_store| !old_store.is_empty() && old_store != store) {
+		let _ = fs::remove_dir_all(get_store_path(&old_store));
+	}
 }
 
+// 使用一次性的登录令牌 (m.login.token) 登录
 pub async fn login_with_token(server: &str, token: &str) -> Option<Client> {
 	set_loading(true);
 
@@ -88,60 +141,640 @@ pub async fn login_with_token(server: &str, token: &str) -> Option<Client> {
 	let client = match Client::builder().server_name(&server_name).build().await {
 		Ok(client) => client,
 		Err(e) => {
-			set_error_message(format!("Failed to connect to server: {}", e.to_string()));
+			set_error_message(format!("Failed to connect to server: {}", e));
 			set_loading(false);
 			return None;
 		}
 	};
 
 	set_info_message("Logging in with token");
-	match client.matrix_auth().login_token(token).send().await {
-		Ok(_) => {},
+	let request = login::v3::Request::new(LoginInfo::Token(login::v3::Token::new(token.to_string())));
+	let response = match client.send(request, None).await {
+		Ok(response) => response,
 		Err(e) => {
-			set_error_message(format!("Failed to login with token: {}", e.to_string()));
+			set_error_message(format!("Failed to login with token: {}", e));
 			set_loading(false);
 			return None;
 		}
 	};
 
-	connect(&client).await;
+	// 登录前还不知道用户名，本地存储由登录返回的用户 ID 决定
+	let Some((client, store, store_passphrase)) = open_store(client.homeserver(), (&response).into()).await else {
+		set_loading(false);
+		return None;
+	};
+
+	let username = response.user_id.localpart().to_string();
+	replace_session(&client, server, &username, &store, store_passphrase);
+
+	Some(client)
+}
 
+// 服务器支持的登录方式
+#[derive(Debug, Clone, Default)]
+pub struct LoginFlows {
+	// 查询时输入的服务器地址
+	pub server: String,
+	pub password: bool,
+	pub token: bool,
+	pub sso: bool,
+	// SSO 可选的身份提供方 (id, 名称)
+	pub identity_providers: Vec<(String, String)>,
+}
+
+impl LoginFlows {
+	// 查询失败时仍然显示密码登录
+	fn fallback(server: String) -> Self {
+		Self {
+			server,
+			password: true,
+			..Default::default()
+		}
+	}
+}
+
+fn set_login_flows(login_flows: Option<LoginFlows>) {
+	let mut client = MATRIX_CLIENT.lock().unwrap();
+	client.login_flows = login_flows;
+}
+
+// 通过 .well-known 找到服务器，并查询其支持的登录方式
+pub async fn discover_login_flows(server: String) {
+	set_login_flows(None);
+	if server.is_empty() {
+		return;
+	}
+
+	let server_name = match ServerName::parse(&server) {
+		Ok(server_name) => server_name,
+		Err(_) => {
+			set_error_message("Invalid server name");
+			set_login_flows(Some(LoginFlows::fallback(server)));
+			return;
+		}
+	};
+
+	set_error_message("");
+	set_info_message("Discovering login methods");
+	let client = match Client::builder().server_name(&server_name).build().await {
+		Ok(client) => client,
+		Err(e) => {
+			set_info_message("");
+			set_error_message(format!("Failed to discover server: {}", e));
+			set_login_flows(Some(LoginFlows::fallback(server)));
+			return;
+		}
+	};
+
+	let response = match client.matrix_auth().get_login_types().await {
+		Ok(response) => response,
+		Err(e) => {
+			set_info_message("");
+			set_error_message(format!("Failed to get login methods: {}", e));
+			set_login_flows(Some(LoginFlows::fallback(server)));
+			return;
+		}
+	};
+
+	let mut login_flows = LoginFlows {
+		server,
+		..Default::default()
+	};
+	for flow in response.flows {
+		match flow {
+			LoginType::Password(_) => login_flows.password = true,
+			LoginType::Token(_) => login_flows.token = true,
+			LoginType::Sso(sso) => {
+				login_flows.sso = true;
+				login_flows.identity_providers = sso.identity_providers.into_iter()
+					.map(|provider| (provider.id, provider.name))
+					.collect();
+			},
+			_ => {}
+		}
+	}
+
+	set_info_message(format!("Server: {}", client.homeserver()));
+	set_login_flows(Some(login_flows));
+}
+
+// 通过本地回环地址接收 SSO 登录返回的令牌
+pub async fn login_sso(server: String, identity_provider: Option<String>) -> Option<Client> {
+	set_error_message("");
+	set_loading(true);
+
+	if server.is_empty() {
+		set_error_message("Missing blank");
+		set_loading(false);
+		return None;
+	}
+
+	let server_name = match ServerName::parse(&server) {
+		Ok(server_name) => server_name,
+		Err(_) => {
+			set_error_message("Invalid server name");
+			set_loading(false);
+			return None;
+		}
+	};
+
+	let listener = match TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await {
+		Ok(listener) => listener,
+		Err(e) => {
+			set_error_message(format!("Failed to start SSO listener: {}", e));
+			set_loading(false);
+			return None;
+		}
+	};
+	let redirect_url = match listener.local_addr() {
+		Ok(addr) => format!("http://{}/", addr),
+		Err(e) => {
+			set_error_message(format!("Failed to start SSO listener: {}", e));
+			set_loading(false);
+			return None;
+		}
+	};
+
+	// 只用来获取 SSO 地址，登录时由 login_with_token 创建带存储的客户端
+	set_info_message("Connecting to server");
+	let client = match Client::builder().server_name(&server_name).build().await {
+		Ok(client) => client,
+		Err(e) => {
+			set_error_message(format!("Failed to connect to server: {}", e));
+			set_loading(false);
+			return None;
+		}
+	};
+	let sso_url = match client.matrix_auth().get_sso_login_url(&redirect_url, identity_provider.as_deref()).await {
+		Ok(url) => url,
+		Err(e) => {
+			set_error_message(format!("Failed to get SSO login url: {}", e));
+			set_loading(false);
+			return None;
+		}
+	};
+
+	open_browser(&sso_url);
+
+	// 等待浏览器时显示地址，用户可以随时取消
+	set_info_message("");
 	set_loading(false);
+	let wait = tokio::spawn(tokio::time::timeout(SSO_TIMEOUT, wait_for_login_token(listener)));
+	set_sso_prompt(Some(SsoPrompt {
+		url: sso_url,
+		task: wait.abort_handle(),
+	}));
+	let result = wait.await;
+	set_sso_prompt(None);
+
+	let token = match result {
+		Ok(Ok(Ok(token))) => token,
+		Ok(Ok(Err(e))) => {
+			set_error_message(format!("Failed to receive SSO login token: {}", e));
+			return None;
+		},
+		Ok(Err(_)) => {
+			set_error_message("SSO login timed out");
+			return None;
+		},
+		Err(_) => {
+			set_info_message("SSO login cancelled");
+			return None;
+		}
+	};
+
+	login_with_token(&server, &token).await
+}
+
+// 等待浏览器完成 SSO 登录时显示的地址
+#[derive(Debug, Clone)]
+pub struct SsoPrompt {
+	pub url: String,
+	task: AbortHandle,
+}
+
+fn set_sso_prompt(prompt: Option<SsoPrompt>) {
+	let mut client = MATRIX_CLIENT.lock().unwrap();
+	client.sso = prompt;
+}
+
+// 停止等待浏览器返回的登录令牌
+pub fn cancel_sso() {
+	let prompt = {
+		let mut client = MATRIX_CLIENT.lock().unwrap();
+		client.sso.take()
+	};
+	if let Some(prompt) = prompt {
+		prompt.task.abort();
+	}
+}
+
+// 等待浏览器跳转回本地地址，从查询参数中取出 loginToken
+async fn wait_for_login_token(listener: TcpListener) -> std::io::Result<String> {
+	loop {
+		let (mut stream, _) = listener.accept().await?;
+
+		let mut buf = vec![0; 8192];
+		let len = stream.read(&mut buf).await?;
+		let request = String::from_utf8_lossy(&buf[..len]);
+		let path = request.lines().next()
+			.and_then(|line| line.split_whitespace().nth(1))
+			.unwrap_or_default();
+
+		let token = Url::parse(&format!("http://localhost{}", path)).ok().and_then(|url| {
+			url.query_pairs()
+				.find(|(key, _)| key == "loginToken")
+				.map(|(_, value)| value.into_owned())
+		});
+
+		// 浏览器可能还会请求 favicon 等其他路径
+		let Some(token) = token else {
+			let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+			continue;
+		};
+
+		let body = "Login complete, you can close this window and return to Matrix Tui.";
+		let response = format!(
+			"HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+			body.len(),
+			body
+		);
+		let _ = stream.write_all(response.as_bytes()).await;
+		return Ok(token);
+	}
+}
+
+#[cfg(target_os = "macos")]
+const OPEN_COMMAND: &str = "open";
+
+#[cfg(target_os = "windows")]
+const OPEN_COMMAND: &str = "explorer";
+
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+const OPEN_COMMAND: &str = "xdg-open";
+
+// 尝试在浏览器中打开，失败时用户仍可以手动打开提示中的地址
+pub fn open_browser(url: &str) {
+	let _ = Command::new(OPEN_COMMAND)
+		.arg(url)
+		.stdin(Stdio::null())
+		.stdout(Stdio::null())
+		.stderr(Stdio::null())
+		.spawn();
+}
+
+// 使用保存的会话恢复登录，继续使用原来的设备
+pub async fn restore_session(account: Account) -> Option<Client> {
+	set_loading(true);
+
+	let user_id = match UserId::parse(&account.user_id) {
+		Ok(user_id) => user_id,
+		Err(_) => {
+			set_error_message("Invalid saved user id");
+			set_loading(false);
+			return None;
+		}
+	};
+
+	set_info_message("Connecting to server");
+	let client = match Client::builder()
+		.homeserver_url(&account.homeserver)
+		.sqlite_store(get_store_path(&account.store), account.get_store_passphrase())
+		.build().await {
+			Ok(client) => client,
+			Err(e) => {
+				set_error_message(format!("Failed to connect to server: {}", e));
+				set_loading(false);
+				return None;
+			}
+		};
+
+	set_info_message("Restoring session");
+	let session = MatrixSession {
+		meta: SessionMeta {
+			user_id,
+			device_id: account.device_id.as_str().into(),
+		},
+		tokens: MatrixSessionTokens {
+			access_token: account.token.clone(),
+			refresh_token: None,
+		},
+	};
+	if let Err(e) = client.restore_session(session).await {
+		set_error_message(format!("Failed to restore session: {}", e));
+		set_loading(false);
+		return None;
+	}
+
+	// 首次同步完成后由同步任务结束加载状态
+	connect(&client);
 
 	Some(client)
 }
 
+// 启用保险库时本地存储也使用随机口令加密
+pub fn new_store_passphrase() -> Option<String> {
+	if SAVING.lock().unwrap().vault.is_some() {
+		Some(random_passphrase())
+	} else {
+		None
+	}
+}
+
+pub fn save_session(client: &Client, server: &str, username: &str, store: &str, store_passphrase: Option<String>) {
+	let Some(session) = client.matrix_auth().session() else {
+		return;
+	};
+
+	let mut saving = SAVING.lock().unwrap();
+	saving.upsert_account(Account {
+		token: session.tokens.access_token,
+		username: username.to_string(),
+		server: server.to_string(),
+		homeserver: client.homeserver().to_string(),
+		user_id: session.meta.user_id.to_string(),
+		device_id: session.meta.device_id.to_string(),
+		store: store.to_string(),
+		store_passphrase: store_passphrase.unwrap_or_default(),
+		secrets: None,
+	});
+	saving.save();
+}
+
 pub fn set_error_message<T: ToString>(msg: T) {
     let mut client = MATRIX_CLIENT.lock().unwrap();
     client.error_message = msg.to_string();
 }
 
-fn set_loading(loading: bool) {
+pub fn set_loading(loading: bool) {
     let mut client = MATRIX_CLIENT.lock().unwrap();
     client.loading = loading;
 }
 
-fn set_connected(connected: bool) {
-	let mut client = MATRIX_CLIENT.lock().unwrap();
-	client.connected = connected;
-}
-
 fn set_info_message<T: ToString>(msg: T) {
 	let mut client = MATRIX_CLIENT.lock().unwrap();
     client.info_message = msg.to_string();
 }
 
+// 当前选中账号的客户端
+pub fn get_client() -> Option<Client> {
+	let client = MATRIX_CLIENT.lock().unwrap();
+	client.active_session().map(|session| session.client.clone())
+}
+
+// 当前账号中的房间，找不到时不提示错误，界面每帧都可能查询
+pub fn get_room(room_id: &RoomId) -> Option<Room> {
+	get_client().and_then(|client| client.get_room(room_id))
+}
+
 pub fn get_matrix_client() -> MatrixClient {
     let client = MATRIX_CLIENT.lock().unwrap();
     client.clone()
 }
 
+// 修改指定账号的状态
+pub fn with_session<R>(user_id: &UserId, f: impl FnOnce(&mut Session) -> R) -> Option<R> {
+	let mut client = MATRIX_CLIENT.lock().unwrap();
+	client.sessions.iter_mut().find(|session| session.user_id == user_id).map(f)
+}
+
+// 首次同步完成后切换到主界面，只触发一次
+pub fn take_show_main() -> bool {
+	let mut client = MATRIX_CLIENT.lock().unwrap();
+	std::mem::take(&mut client.show_main)
+}
+
+// 退出最后一个账号后切换到登录界面，只触发一次
+pub fn take_show_login() -> bool {
+	let mut client = MATRIX_CLIENT.lock().unwrap();
+	std::mem::take(&mut client.show_login)
+}
+
+pub fn set_open_room(room_id: OwnedRoomId) {
+	let mut client = MATRIX_CLIENT.lock().unwrap();
+	client.open_room = Some(room_id);
+}
+
+pub fn take_open_room() -> Option<OwnedRoomId> {
+	let mut client = MATRIX_CLIENT.lock().unwrap();
+	client.open_room.take()
+}
+
+// 切换到下一个已连接的账号
+pub fn switch_account() {
+	let mut client = MATRIX_CLIENT.lock().unwrap();
+	let connected: Vec<OwnedUserId> = client.sessions.iter()
+		.filter(|session| session.connected)
+		.map(|session| session.user_id.clone())
+		.collect();
+	if connected.is_empty() {
+		return;
+	}
+
+	let next = match client.active.as_ref().and_then(|active| connected.iter().position(|user_id| user_id == active)) {
+		Some(index) => (index + 1) % connected.len(),
+		None => 0,
+	};
+	client.active = Some(connected[next].clone());
+	client.chosen = Some(connected[next].clone());
+}
+
+// 选择启动后或登录后要使用的账号
+pub fn choose_account(user_id: &UserId) {
+	let mut client = MATRIX_CLIENT.lock().unwrap();
+	client.chosen = Some(user_id.to_owned());
+}
+
+// 切换到已登录的账号
+pub fn activate_account(user_id: &UserId) -> bool {
+	let mut client = MATRIX_CLIENT.lock().unwrap();
+	if client.sessions.iter().any(|session| session.user_id == user_id && session.connected) {
+		client.active = Some(user_id.to_owned());
+		client.chosen = Some(user_id.to_owned());
+		client.show_main = true;
+		true
+	} else {
+		false
+	}
+}
+
+// 退出当前账号：注销服务器上的设备，并删除保存的令牌和本地存储
+pub async fn logout() {
+	let Some(client) = get_client() else {
+		return;
+	};
+	let Some(user_id) = client.user_id().map(|user_id| user_id.to_owned()) else {
+		return;
+	};
+
+	set_error_message("");
+	set_info_message("Logging out");
+	if let Err(e) = client.matrix_auth().logout().await {
+		// 令牌已经失效时仍然清理本地数据
+		if !matches!(e.client_api_error_kind(), Some(ErrorKind::UnknownToken { .. })) {
+			set_info_message("");
+			set_error_message(format!("Failed to log out: {}", e));
+			return;
+		}
+	}
+
+	// 先停止同步任务并释放客户端，再删除本地存储
+	{
+		let mut matrix_client = MATRIX_CLIENT.lock().unwrap();
+		for session in matrix_client.sessions.iter().filter(|session| session.user_id == user_id) {
+			if let Some(sync_task) = &session.sync_task {
+				sync_task.abort();
+			}
+		}
+		matrix_client.sessions.retain(|session| session.user_id != user_id);
+		if matrix_client.active.as_ref() == Some(&user_id) {
+			matrix_client.active = matrix_client.sessions.iter()
+				.find(|session| session.connected)
+				.map(|session| session.user_id.clone());
+			// 没有其他可用账号时回到登录界面
+			matrix_client.show_login = matrix_client.active.is_none();
+		}
+	}
+	drop(client);
+
+	// 保留用户名和服务器，方便重新登录
+	let mut saving = SAVING.lock().unwrap();
+	if let Some(account) = saving.accounts.iter_mut().find(|account| account.user_id == user_id.as_str()) {
+		let _ = fs::remove_dir_all(get_store_path(&account.store));
+		account.token.clear();
+		account.device_id.clear();
+		account.store_passphrase.clear();
+		account.secrets = None;
+	}
+	saving.save();
+
+	set_info_message(format!("Logged out {}", user_id));
+}
+
+// 删除当前账号的其他所有设备
+pub async fn logout_other_sessions() {
+	let Some(client) = get_client() else {
+		return;
+	};
+	let Some(device_id) = client.device_id() else {
+		return;
+	};
+
+	set_error_message("");
+	let devices = match client.devices().await {
+		Ok(response) => response.devices,
+		Err(e) => {
+			set_error_message(format!("Failed to get sessions: {}", e));
+			return;
+		}
+	};
+	let device_ids: Vec<OwnedDeviceId> = devices.into_iter()
+		.map(|device| device.device_id)
+		.filter(|id| id != device_id)
+		.collect();
+	if device_ids.is_empty() {
+		set_info_message("No other sessions");
+		return;
+	}
+
+	set_info_message("Logging out other sessions");
+	if let Err(e) = delete_devices(&client, &device_ids, "Log out other sessions").await {
+		set_info_message("");
+		set_error_message(format!("Failed to log out other sessions: {}", e));
+		return;
+	}
+
+	set_info_message(format!("Logged out {} other sessions", device_ids.len()));
+}
+
+// 删除设备，服务器要求再次认证时弹窗认证
+pub async fn delete_devices(client: &Client, device_ids: &[OwnedDeviceId], title: &str) -> Result<(), UiaaError> {
+	with_uiaa(client, title, |auth| client.delete_devices(device_ids, auth)).await?;
+	Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct RoomItem {
+	pub room_id: OwnedRoomId,
+	pub name: String,
+	pub unread: u64,
+	pub highlight: u64,
+	pub encrypted: bool,
+	// 根据 m.direct 账号数据判断的私聊
+	pub direct: bool,
+	pub is_space: bool,
+	// 空间声明的子房间和房间声明的父空间
+	pub children: Vec<OwnedRoomId>,
+	pub parents: Vec<OwnedRoomId>,
+}
+
+// 收到邀请但还没有加入的房间
+#[derive(Debug, Clone)]
+pub struct InviteItem {
+	pub room_id: OwnedRoomId,
+	pub name: String,
+	pub inviter: Option<OwnedUserId>,
+	pub inviter_name: Option<String>,
+	pub encrypted: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SyncState {
+	#[default] Stopped,
+	InitialSync,
+	Syncing,
+	BackingOff(Duration),
+	Offline(Duration),
+}
+
+impl Display for SyncState {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			SyncState::Stopped => write!(f, "Stopped"),
+			SyncState::InitialSync => write!(f, "Initial sync"),
+			SyncState::Syncing => write!(f, "Syncing"),
+			SyncState::BackingOff(delay) => write!(f, "Sync failed, retrying in {}s", delay.as_secs()),
+			SyncState::Offline(delay) => write!(f, "Offline, retrying in {}s", delay.as_secs()),
+		}
+	}
+}
+
+// 一个已登录账号的状态
+#[derive(Debug, Clone)]
+pub struct Session {
+	pub user_id: OwnedUserId,
+	pub connected: bool,
+	pub sync_state: SyncState,
+	pub rooms: Vec<RoomItem>,
+	pub invites: Vec<InviteItem>,
+	pub timelines: HashMap<OwnedRoomId, Timeline>,
+	pub verification: Option<VerificationDialog>,
+	pub devices: Vec<DeviceItem>,
+	// 私聊对象的搜索结果
+	pub user_search: Vec<UserItem>,
+	// 正在浏览的空间层级
+	pub hierarchy: Option<Hierarchy>,
+	client: Client,
+	sync_task: Option<AbortHandle>,
+}
+
 #[derive(Debug, Clone)]
 pub struct MatrixClient {
 	pub error_message: String,
 	pub info_message: String,
-	pub connected: bool,
-	pub loading: bool
+	pub loading: bool,
+	pub sessions: Vec<Session>,
+	pub active: Option<OwnedUserId>,
+	pub login_flows: Option<LoginFlows>,
+	pub registration: Option<Registration>,
+	pub uiaa: Option<UiaaDialog>,
+	pub sso: Option<SsoPrompt>,
+	show_main: bool,
+	show_login: bool,
+	// 用户选择的账号，它完成首次同步后成为当前账号
+	chosen: Option<OwnedUserId>,
+	// 加入或创建后需要打开的房间
+	open_room: Option<OwnedRoomId>,
 }
 
 impl MatrixClient {
@@ -149,30 +782,247 @@ impl MatrixClient {
 		Self {
 			error_message: String::new(),
 			info_message: String::new(),
-			connected: false,
 			loading: false,
+			sessions: Vec::new(),
+			active: None,
+			show_main: false,
+			show_login: false,
+			chosen: None,
+			login_flows: None,
+			registration: None,
+			uiaa: None,
+			sso: None,
+			open_room: None,
 		}
 	}
+
+	pub fn active_session(&self) -> Option<&Session> {
+		let active = self.active.as_ref()?;
+		self.sessions.iter().find(|session| &session.user_id == active)
+	}
+
+	pub fn is_logged_in(&self, user_id: &str) -> bool {
+		self.sessions.iter().any(|session| session.user_id == user_id)
+	}
 }
 
-async fn connect(client: &Client) {
-	client.add_event_handler(|ev: SyncRoomMessageEvent| async move {
-		match ev.event_type() {
-			MessageLikeEventType::Message => {
+// 根据已加入的房间重建房间列表
+pub async fn refresh_rooms(client: &Client) {
+	let Some(user_id) = client.user_id() else {
+		return;
+	};
+	let mut rooms = Vec::new();
 
-			}
-			_ => {}
-		}
+	for room in client.joined_rooms() {
+		let targets = room.direct_targets();
+		let name = match room.display_name().await {
+			Ok(name) => name.to_string(),
+			Err(_) => room.name().unwrap_or_else(|| room.room_id().to_string()),
+		};
+		// 私聊显示对方的昵称
+		let name = match targets.iter().next() {
+			Some(target) if room.name().is_none() => match room.get_member_no_sync(target).await {
+				Ok(Some(member)) => member.name().to_string(),
+				_ => name,
+			},
+			_ => name,
+		};
+		let counts = room.unread_notification_counts();
+
+		rooms.push(RoomItem {
+			room_id: room.room_id().to_owned(),
+			name,
+			unread: counts.notification_count,
+			highlight: counts.highlight_count,
+			encrypted: room.is_encrypted().await.unwrap_or_default(),
+			direct: !targets.is_empty(),
+			is_space: room.is_space(),
+			children: space_children(&room).await,
+			parents: space_parents(&room).await,
+		});
+	}
+
+	rooms.sort_by_key(|room| room.name.to_lowercase());
+
+	let mut invites = Vec::new();
+	for room in client.invited_rooms() {
+		let name = match room.display_name().await {
+			Ok(name) => name.to_string(),
+			Err(_) => room.name().unwrap_or_else(|| room.room_id().to_string()),
+		};
+		let inviter = match room.invite_details().await {
+			Ok(invite) => invite.inviter,
+			Err(_) => None,
+		};
+
+		invites.push(InviteItem {
+			room_id: room.room_id().to_owned(),
+			name,
+			inviter: inviter.as_ref().map(|member| member.user_id().to_owned()),
+			inviter_name: inviter.as_ref().map(|member| member.name().to_string()),
+			// 邀请中只有部分状态，不能向服务器请求房间的加密状态
+			encrypted: BaseRoom::is_encrypted(&room),
+		});
+	}
+
+	with_session(user_id, |session| {
+		session.rooms = rooms;
+		session.invites = invites;
 	});
+}
 
-	set_info_message("Syncing with server");
-	match client.sync(SyncSettings::default()).await {
-		Ok(_) => {},
-		Err(e) => {
-			set_error_message(format!("Failed to sync with server: {}", e.to_string()));
-			return;
+// 令牌失效、账号被停用等错误重试也无法恢复
+fn is_fatal_sync_error(e: &matrix_sdk::Error) -> bool {
+	matches!(
+		e.client_api_error_kind(),
+		Some(ErrorKind::UnknownToken { .. } | ErrorKind::Forbidden | ErrorKind::UserDeactivated)
+	)
+}
+
+fn backoff_delay(failures: u32) -> Duration {
+	let delay = MIN_BACKOFF.saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)));
+	delay.min(MAX_BACKOFF)
+}
+
+async fn on_sync_success(client: &Client, user_id: &UserId) {
+	refresh_rooms(client).await;
+	retry_decryption(client).await;
+
+	let first_sync = with_session(user_id, |session| {
+		session.sync_state = SyncState::Syncing;
+		!std::mem::replace(&mut session.connected, true)
+	}).unwrap_or_default();
+
+	// 用户选择的账号完成首次同步后成为当前账号，没有当前账号时先使用最早完成的账号
+	if first_sync {
+		let mut matrix_client = MATRIX_CLIENT.lock().unwrap();
+		if matrix_client.chosen.as_deref() == Some(user_id) || matrix_client.active.is_none() {
+			matrix_client.info_message.clear();
+			matrix_client.loading = false;
+			matrix_client.active = Some(user_id.to_owned());
+			matrix_client.show_main = true;
 		}
+	}
+}
+
+async fn on_sync_failure(e: matrix_sdk::Error, user_id: &UserId, failures: u32) {
+	let delay = backoff_delay(failures);
+
+	let connected = with_session(user_id, |session| {
+		if failures >= OFFLINE_FAILURES {
+			session.sync_state = SyncState::Offline(delay);
+		} else {
+			session.sync_state = SyncState::BackingOff(delay);
+		}
+		session.connected
+	}).unwrap_or_default();
+
+	if !connected {
+		set_info_message(format!("Failed to sync with server: {}, retrying in {}s", e, delay.as_secs()));
+	}
+
+	tokio::time::sleep(delay).await;
+}
+
+// 在后台持续同步，网络错误时按指数退避重试
+async fn sync_loop(client: Client, user_id: OwnedUserId) {
+	// 连续同步失败的次数，用于计算退避时间
+	let failures = Arc::new(AtomicU32::new(0));
+	with_session(&user_id, |session| session.sync_state = SyncState::InitialSync);
+
+	let result = client.sync_with_result_callback(SyncSettings::default(), |response| {
+		let client = client.clone();
+		let user_id = user_id.clone();
+		let failures = failures.clone();
+		async move {
+			// 账号已经退出登录
+			if with_session(&user_id, |_| ()).is_none() {
+				return Ok(LoopCtrl::Break);
+			}
+
+			match response {
+				Ok(_) => {
+					failures.store(0, Ordering::SeqCst);
+					on_sync_success(&client, &user_id).await;
+				},
+				Err(e) if is_fatal_sync_error(&e) => return Err(e),
+				Err(e) => {
+					let failures = failures.fetch_add(1, Ordering::SeqCst) + 1;
+					on_sync_failure(e, &user_id, failures).await;
+				},
+			}
+			Ok(LoopCtrl::Continue)
+		}
+	}).await;
+
+	if let Err(e) = result {
+		set_error_message(format!("Failed to sync with server: {}", e));
+		with_session(&user_id, |session| session.sync_state = SyncState::Stopped);
+		set_loading(false);
+	}
+}
+
+pub fn connect(client: &Client) {
+	let Some(user_id) = client.user_id().map(|user_id| user_id.to_owned()) else {
+		return;
 	};
 
-	set_connected(true);
+	{
+		let mut matrix_client = MATRIX_CLIENT.lock().unwrap();
+		// 重新登录同一账号时停止之前的同步任务
+		for session in matrix_client.sessions.iter().filter(|session| session.user_id == user_id) {
+			if let Some(sync_task) = &session.sync_task {
+				sync_task.abort();
+			}
+		}
+		matrix_client.sessions.retain(|session| session.user_id != user_id);
+		matrix_client.sessions.push(Session {
+			user_id: user_id.clone(),
+			connected: false,
+			sync_state: SyncState::Stopped,
+			rooms: Vec::new(),
+			invites: Vec::new(),
+			timelines: HashMap::new(),
+			verification: None,
+			devices: Vec::new(),
+			user_search: Vec::new(),
+			hierarchy: None,
+			client: client.clone(),
+			sync_task: None,
+		});
+	}
+
+	client.add_event_handler(handle_sync_message);
+	client.add_event_handler(handle_sync_encrypted);
+	client.add_event_handler(handle_sync_redaction);
+	client.add_event_handler(handle_sync_reaction);
+	client.add_event_handler(handle_verification_request);
+	client.add_event_handler(handle_verification_start);
+	client.add_event_handler(handle_invite);
+
+	set_info_message("Syncing with server");
+	let sync_task = tokio::spawn(sync_loop(client.clone(), user_id.clone())).abort_handle();
+	with_session(&user_id, |session| session.sync_task = Some(sync_task));
+
+	// 先显示本地存储中已有的房间
+	let client = client.clone();
+	tokio::spawn(async move {
+		refresh_rooms(&client).await;
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn backoff_doubles_until_the_limit() {
+		assert_eq!(backoff_delay(0), MIN_BACKOFF);
+		assert_eq!(backoff_delay(1), Duration::from_secs(1));
+		assert_eq!(backoff_delay(2), Duration::from_secs(2));
+		assert_eq!(backoff_delay(3), Duration::from_secs(4));
+		assert_eq!(backoff_delay(6), Duration::from_secs(32));
+		assert_eq!(backoff_delay(7), MAX_BACKOFF);
+		assert_eq!(backoff_delay(u32::MAX), MAX_BACKOFF);
+	}
 }
@@ -6,7 +6,7 @@ use crate::app::{App, FocusArea};
 pub fn get_nearest_focus_area(app: &App, direction: KeyCode) -> Option<FocusArea> {
 	if let Some(current_screen) = app.focus_area_positions.get(&app.current_focus) {
 		let mut nearest_focus_area: Option<FocusArea> = None;
-        let mut min_distance = std::isize::MAX;
+        let mut min_distance = isize::MAX;
 
 		for (focus_area, rect) in app.focus_area_positions.iter() {
 			if *focus_area == app.current_focus {
@@ -36,8 +36,8 @@ pub fn get_nearest_focus_area(app: &App, direction: KeyCode) -> Option<FocusArea
 // 寻找到最靠左，最靠上的 rect 并返回其 FocusArea Enum
 pub fn get_top_left_focus_area(app: &App) -> Option<FocusArea> {
 	let mut top_left_focus_area: Option<FocusArea> = None;
-	let mut min_x = std::u16::MAX;
-	let mut min_y = std::u16::MAX;
+	let mut min_x = u16::MAX;
+	let mut min_y = u16::MAX;
 
 	for (focus_area, rect) in app.focus_area_positions.iter() {
 		if rect.x < min_x {
@@ -0,0 +1,314 @@
+use matrix_sdk::{
+	matrix_auth::{MatrixSession, MatrixSessionTokens},
+	ruma::{
+		api::client::{
+			account::{register, request_registration_token_via_email},
+			uiaa::{AuthData, AuthType, Dummy, RegistrationToken, UiaaInfo},
+		},
+		serde::JsonObject,
+		ClientSecret, OwnedClientSecret, OwnedSessionId,
+	},
+	Client, ServerName, SessionMeta,
+};
+use serde_json::json;
+
+use crate::{
+	matrix::{open_store, replace_session, set_error_message, MATRIX_CLIENT},
+	uiaa::{is_supported, terms_policies, TERMS_AUTH_TYPE},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegisterStage {
+	// 等待服务器响应
+	Pending,
+	// 需要同意的条款 (名称, 链接)
+	Terms(Vec<(String, String)>),
+	RegistrationToken,
+	// 输入用于验证的邮箱地址
+	Email,
+	// 已发送验证邮件，等待用户点击邮件中的链接
+	EmailSent(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Registration {
+	pub server: String,
+	pub username: String,
+	pub stage: RegisterStage,
+	password: String,
+	// 用户交互认证的会话 ID
+	session: Option<String>,
+	client_secret: OwnedClientSecret,
+	email_sid: Option<OwnedSessionId>,
+	send_attempt: u32,
+	client: Client,
+}
+
+fn get_registration() -> Option<Registration> {
+	let client = MATRIX_CLIENT.lock().unwrap();
+	client.registration.clone()
+}
+
+fn set_registration(registration: Option<Registration>) {
+	let mut client = MATRIX_CLIENT.lock().unwrap();
+	client.registration = registration;
+}
+
+fn set_stage(stage: RegisterStage) {
+	let mut client = MATRIX_CLIENT.lock().unwrap();
+	if let Some(registration) = client.registration.as_mut() {
+		registration.stage = stage;
+	}
+}
+
+// 注册时支持的步骤
+const REGISTRATION_STAGES: &[AuthType] = &[AuthType::Dummy, AuthType::RegistrationToken, AuthType::EmailIdentity];
+
+// 选择第一个所有步骤都支持的流程，返回其中下一个未完成的步骤
+fn next_auth_stage(info: &UiaaInfo) -> Result<Option<AuthType>, String> {
+	let Some(flow) = info.flows.iter().find(|flow| flow.stages.iter().all(|stage| is_supported(stage, REGISTRATION_STAGES))) else {
+		let stages: Vec<String> = info.flows.iter()
+			.flat_map(|flow| flow.stages.iter().map(|stage| stage.to_string()))
+			.collect();
+		return Err(format!("The server requires unsupported registration steps: {}", stages.join(", ")));
+	};
+
+	Ok(flow.stages.iter().find(|stage| !info.completed.contains(stage)).cloned())
+}
+
+// 使用表单中的用户名和密码开始注册
+pub async fn register(server: String, username: String, password: String) {
+	set_error_message("");
+
+	if server.is_empty() || username.is_empty() || password.is_empty() {
+		set_error_message("Missing blank");
+		return;
+	}
+
+	let server_name = match ServerName::parse(&server) {
+		Ok(server_name) => server_name,
+		Err(_) => {
+			set_error_message("Invalid server name");
+			return;
+		}
+	};
+
+	// 注册完成后才创建本地存储
+	let client = match Client::builder().server_name(&server_name).build().await {
+		Ok(client) => client,
+		Err(e) => {
+			set_error_message(format!("Failed to connect to server: {}", e));
+			return;
+		}
+	};
+
+	set_registration(Some(Registration {
+		server,
+		username,
+		stage: RegisterStage::Pending,
+		password,
+		session: None,
+		client_secret: ClientSecret::new(),
+		email_sid: None,
+		send_attempt: 0,
+		client,
+	}));
+
+	submit(None).await;
+}
+
+// 提交一个认证步骤，哑步骤会自动完成
+async fn submit(auth: Option<AuthData>) {
+	let mut auth = auth;
+	loop {
+		let Some(registration) = get_registration() else {
+			return;
+		};
+		set_stage(RegisterStage::Pending);
+
+		let mut request = register::v3::Request::new();
+		request.username = Some(registration.username.clone());
+		request.password = Some(registration.password.clone());
+		request.initial_device_display_name = Some("Matrix Tui".to_string());
+		request.auth = auth.take();
+
+		let info = match registration.client.send(request, None).await {
+			Ok(response) => {
+				finish(registration, response).await;
+				return;
+			},
+			Err(e) => match e.as_uiaa_response() {
+				Some(info) => info.clone(),
+				None => {
+					set_error_message(format!("Failed to register: {}", e));
+					set_registration(None);
+					return;
+				}
+			}
+		};
+
+		if let Some(error) = &info.auth_error {
+			set_error_message(&error.message);
+		}
+
+		let session = info.session.clone();
+		{
+			let mut client = MATRIX_CLIENT.lock().unwrap();
+			if let Some(registration) = client.registration.as_mut() {
+				registration.session = session.clone();
+			}
+		}
+
+		let stage = match next_auth_stage(&info) {
+			Ok(Some(stage)) => stage,
+			Ok(None) => {
+				set_error_message("The server did not accept the registration");
+				set_registration(None);
+				return;
+			},
+			Err(e) => {
+				set_error_message(e);
+				set_registration(None);
+				return;
+			}
+		};
+
+		match stage {
+			AuthType::Dummy => {
+				let mut dummy = Dummy::new();
+				dummy.session = session;
+				auth = Some(AuthData::Dummy(dummy));
+			},
+			AuthType::RegistrationToken => {
+				set_stage(RegisterStage::RegistrationToken);
+				return;
+			},
+			AuthType::EmailIdentity => {
+				// 邮件已经发出时继续等待用户点击链接
+				if !matches!(registration.stage, RegisterStage::EmailSent(_)) {
+					set_stage(RegisterStage::Email);
+				} else {
+					set_stage(registration.stage);
+				}
+				return;
+			},
+			_ => {
+				set_stage(RegisterStage::Terms(terms_policies(&info)));
+				return;
+			}
+		}
+	}
+}
+
+async fn finish(registration: Registration, response: register::v3::Response) {
+	let (Some(access_token), Some(device_id)) = (response.access_token, response.device_id) else {
+		set_error_message("The server did not log in the new account");
+		set_registration(None);
+		return;
+	};
+
+	let session = MatrixSession {
+		meta: SessionMeta {
+			user_id: response.user_id,
+			device_id,
+		},
+		tokens: MatrixSessionTokens {
+			access_token,
+			refresh_token: response.refresh_token,
+		},
+	};
+	let Some((client, store, store_passphrase)) = open_store(registration.client.homeserver(), session).await else {
+		set_registration(None);
+		return;
+	};
+
+	// 首次同步完成后切换到主界面
+	set_registration(None);
+	replace_session(&client, &registration.server, &registration.username, &store, store_passphrase);
+}
+
+pub async fn accept_terms() {
+	let Some(registration) = get_registration() else {
+		return;
+	};
+
+	match AuthData::new(TERMS_AUTH_TYPE, registration.session, Default::default()) {
+		Ok(auth) => submit(Some(auth)).await,
+		Err(e) => set_error_message(format!("Failed to accept terms: {}", e)),
+	}
+}
+
+pub async fn submit_registration_token(token: String) {
+	let Some(registration) = get_registration() else {
+		return;
+	};
+	if token.is_empty() {
+		set_error_message("Missing blank");
+		return;
+	}
+
+	let mut auth = RegistrationToken::new(token);
+	auth.session = registration.session;
+	submit(Some(AuthData::RegistrationToken(auth))).await;
+}
+
+// 请求服务器向邮箱发送验证链接
+pub async fn request_email(email: String) {
+	let Some(registration) = get_registration() else {
+		return;
+	};
+	if email.is_empty() {
+		set_error_message("Missing blank");
+		return;
+	}
+
+	set_error_message("");
+	set_stage(RegisterStage::Pending);
+	let send_attempt = registration.send_attempt + 1;
+	let request = request_registration_token_via_email::v3::Request::new(
+		registration.client_secret.clone(),
+		email.clone(),
+		send_attempt.into(),
+	);
+
+	match registration.client.send(request, None).await {
+		Ok(response) => {
+			let mut client = MATRIX_CLIENT.lock().unwrap();
+			if let Some(registration) = client.registration.as_mut() {
+				registration.email_sid = Some(response.sid);
+				registration.send_attempt = send_attempt;
+				registration.stage = RegisterStage::EmailSent(email);
+			}
+		},
+		Err(e) => {
+			set_error_message(format!("Failed to send verification email: {}", e));
+			set_stage(RegisterStage::Email);
+		}
+	}
+}
+
+// 用户点击邮件中的链接后继续注册
+pub async fn confirm_email() {
+	let Some(registration) = get_registration() else {
+		return;
+	};
+	let Some(sid) = registration.email_sid else {
+		return;
+	};
+
+	// 由服务器自己验证邮箱时不需要身份服务器的信息
+	let mut data = JsonObject::new();
+	data.insert("threepid_creds".to_string(), json!({
+		"sid": sid,
+		"client_secret": registration.client_secret,
+	}));
+
+	match AuthData::new(AuthType::EmailIdentity.as_str(), registration.session, data) {
+		Ok(auth) => submit(Some(auth)).await,
+		Err(e) => set_error_message(format!("Failed to verify email: {}", e)),
+	}
+}
+
+pub fn cancel() {
+	set_registration(None);
+}
@@ -0,0 +1,334 @@
+use matrix_sdk::{
+	ruma::{
+		api::client::room::{create_room::v3::{Request as CreateRoomRequest, RoomPreset}, Visibility},
+		events::{
+			room::{encryption::RoomEncryptionEventContent, member::{MembershipState, StrippedRoomMemberEvent}},
+			InitialStateEvent,
+		},
+		matrix_uri::MatrixId,
+		MatrixToUri, OwnedRoomId, OwnedRoomOrAliasId, OwnedServerName, OwnedUserId, UserId,
+	},
+	Client, Room,
+};
+
+use crate::matrix::{get_client, get_room, refresh_rooms, set_error_message, set_open_room, with_session};
+
+// 用户目录中搜索到的用户
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserItem {
+	pub user_id: OwnedUserId,
+	pub display_name: Option<String>,
+}
+
+// 同步收到新的邀请时立即更新邀请列表
+pub async fn handle_invite(ev: StrippedRoomMemberEvent, client: Client) {
+	if ev.content.membership != MembershipState::Invite || client.user_id() != Some(&ev.state_key) {
+		return;
+	}
+
+	refresh_rooms(&client).await;
+}
+
+// 用户操作的房间，找不到时提示错误
+fn find_room(room_id: &OwnedRoomId) -> Option<Room> {
+	let room = get_room(room_id);
+	if room.is_none() {
+		set_error_message("Room not found");
+	}
+	room
+}
+
+pub async fn accept_invite(room_id: OwnedRoomId) {
+	let Some(client) = get_client() else {
+		return;
+	};
+	let Some(room) = find_room(&room_id) else {
+		return;
+	};
+
+	set_error_message("");
+	if let Err(e) = room.join().await {
+		set_error_message(format!("Failed to join room: {}", e));
+		return;
+	}
+
+	refresh_rooms(&client).await;
+}
+
+// 拒绝邀请，可以同时忽略发出邀请的用户
+pub async fn decline_invite(room_id: OwnedRoomId, ignore_inviter: bool) {
+	let Some(client) = get_client() else {
+		return;
+	};
+	let Some(room) = find_room(&room_id) else {
+		return;
+	};
+
+	set_error_message("");
+	if ignore_inviter {
+		let inviter = room.invite_details().await.ok().and_then(|invite| invite.inviter);
+		if let Some(inviter) = inviter {
+			if let Err(e) = client.account().ignore_user(inviter.user_id()).await {
+				set_error_message(format!("Failed to ignore user: {}", e));
+				return;
+			}
+		}
+	}
+
+	if let Err(e) = room.leave().await {
+		set_error_message(format!("Failed to decline invite: {}", e));
+		return;
+	}
+
+	refresh_rooms(&client).await;
+}
+
+// 解析房间 ID、别名或 matrix.to 链接，链接中可能带有用于加入的服务器
+fn parse_join_target(target: &str) -> Option<(OwnedRoomOrAliasId, Vec<OwnedServerName>)> {
+	if let Ok(uri) = MatrixToUri::parse(target) {
+		let id: OwnedRoomOrAliasId = match uri.id() {
+			MatrixId::Room(room_id) => room_id.clone().into(),
+			MatrixId::RoomAlias(alias) => alias.clone().into(),
+			MatrixId::Event(room_id, _) => room_id.clone(),
+			_ => return None,
+		};
+		return Some((id, uri.via().to_vec()));
+	}
+
+	OwnedRoomOrAliasId::try_from(target).ok().map(|id| (id, Vec::new()))
+}
+
+pub async fn join_room(target: String) {
+	let Some(client) = get_client() else {
+		return;
+	};
+	let target = target.trim();
+	if target.is_empty() {
+		set_error_message("Missing blank");
+		return;
+	}
+	let Some((id, via)) = parse_join_target(target) else {
+		set_error_message("Invalid room address");
+		return;
+	};
+
+	set_error_message("");
+	let room = match client.join_room_by_id_or_alias(&id, &via).await {
+		Ok(room) => room,
+		Err(e) => {
+			set_error_message(format!("Failed to join room: {}", e));
+			return;
+		}
+	};
+
+	refresh_rooms(&client).await;
+	set_open_room(room.room_id().to_owned());
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NewRoom {
+	pub name: String,
+	pub topic: String,
+	pub public: bool,
+	pub encrypted: bool,
+	// 用空格或逗号分隔的用户 ID
+	pub invitees: String,
+}
+
+pub async fn create_room(new_room: NewRoom) {
+	let Some(client) = get_client() else {
+		return;
+	};
+	if new_room.name.trim().is_empty() {
+		set_error_message("Missing blank");
+		return;
+	}
+
+	let mut invite = Vec::new();
+	for user_id in new_room.invitees.split([' ', ',']).filter(|user_id| !user_id.is_empty()) {
+		match OwnedUserId::try_from(user_id) {
+			Ok(user_id) => invite.push(user_id),
+			Err(_) => {
+				set_error_message(format!("Invalid user ID: {}", user_id));
+				return;
+			}
+		}
+	}
+
+	let mut request = CreateRoomRequest::new();
+	request.name = Some(new_room.name.trim().to_string());
+	if !new_room.topic.trim().is_empty() {
+		request.topic = Some(new_room.topic.trim().to_string());
+	}
+	if new_room.public {
+		request.visibility = Visibility::Public;
+		request.preset = Some(RoomPreset::PublicChat);
+	} else {
+		request.visibility = Visibility::Private;
+		request.preset = Some(RoomPreset::PrivateChat);
+	}
+	if new_room.encrypted {
+		let encryption = InitialStateEvent::new(RoomEncryptionEventContent::with_recommended_defaults());
+		request.initial_state.push(encryption.to_raw_any());
+	}
+	request.invite = invite;
+
+	set_error_message("");
+	let room = match client.create_room(request).await {
+		Ok(room) => room,
+		Err(e) => {
+			set_error_message(format!("Failed to create room: {}", e));
+			return;
+		}
+	};
+
+	refresh_rooms(&client).await;
+	set_open_room(room.room_id().to_owned());
+}
+
+// 离开房间，可以同时让服务器忘记该房间
+pub async fn leave_room(room_id: OwnedRoomId, forget: bool) {
+	let Some(client) = get_client() else {
+		return;
+	};
+	let Some(room) = find_room(&room_id) else {
+		return;
+	};
+
+	set_error_message("");
+	if let Err(e) = room.leave().await {
+		set_error_message(format!("Failed to leave room: {}", e));
+		return;
+	}
+
+	if forget {
+		if let Err(e) = room.forget().await {
+			set_error_message(format!("Failed to forget room: {}", e));
+		}
+	}
+
+	refresh_rooms(&client).await;
+}
+
+// 在用户目录中搜索私聊对象，输入完整的用户 ID 时即使目录中没有也可以直接使用
+pub async fn search_users(term: String) {
+	let Some(client) = get_client() else {
+		return;
+	};
+	let Some(own_user_id) = client.user_id().map(|user_id| user_id.to_owned()) else {
+		return;
+	};
+	let term = term.trim().to_string();
+	if term.is_empty() {
+		set_error_message("Missing blank");
+		return;
+	}
+
+	set_error_message("");
+	let mut users: Vec<UserItem> = match client.search_users(&term, 20).await {
+		Ok(response) => response.results.into_iter()
+			.filter(|user| user.user_id != own_user_id)
+			.map(|user| UserItem {
+				user_id: user.user_id,
+				display_name: user.display_name,
+			})
+			.collect(),
+		Err(e) => {
+			set_error_message(format!("Failed to search users: {}", e));
+			Vec::new()
+		}
+	};
+
+	if let Ok(user_id) = UserId::parse(&term) {
+		if user_id != own_user_id && !users.iter().any(|user| user.user_id == user_id) {
+			users.insert(0, UserItem {
+				user_id,
+				display_name: None,
+			});
+		}
+	}
+
+	with_session(&own_user_id, |session| session.user_search = users);
+}
+
+pub fn clear_user_search() {
+	let Some(client) = get_client() else {
+		return;
+	};
+	if let Some(user_id) = client.user_id() {
+		with_session(user_id, |session| session.user_search.clear());
+	}
+}
+
+// 打开与该用户已有的私聊，没有时创建一个加密的私聊房间
+pub async fn start_dm(user_id: OwnedUserId) {
+	let Some(client) = get_client() else {
+		return;
+	};
+
+	set_error_message("");
+	let room = match client.get_dm_room(&user_id) {
+		Some(room) => {
+			// 已有的私聊没有加密时开启加密
+			if !room.is_encrypted().await.unwrap_or_default() {
+				if let Err(e) = room.enable_encryption().await {
+					set_error_message(format!("Failed to enable encryption: {}", e));
+					return;
+				}
+			}
+			room
+		},
+		None => match client.create_dm(&user_id).await {
+			Ok(room) => room,
+			Err(e) => {
+				set_error_message(format!("Failed to create direct message: {}", e));
+				return;
+			}
+		},
+	};
+
+	refresh_rooms(&client).await;
+	set_open_room(room.room_id().to_owned());
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn target(input: &str) -> Option<(String, Vec<String>)> {
+		parse_join_target(input).map(|(id, via)| (id.to_string(), via.iter().map(|server| server.to_string()).collect()))
+	}
+
+	#[test]
+	fn parses_room_ids_and_aliases() {
+		assert_eq!(target("!room:example.org"), Some(("!room:example.org".to_string(), vec![])));
+		assert_eq!(target("#room:example.org"), Some(("#room:example.org".to_string(), vec![])));
+	}
+
+	#[test]
+	fn parses_matrix_to_links() {
+		assert_eq!(
+			target("https://matrix.to/#/%23room:example.org"),
+			Some(("#room:example.org".to_string(), vec![])),
+		);
+		assert_eq!(
+			target("https://matrix.to/#/!room:example.org?via=example.org&via=other.org"),
+			Some(("!room:example.org".to_string(), vec!["example.org".to_string(), "other.org".to_string()])),
+		);
+	}
+
+	#[test]
+	fn parses_event_links_as_their_room() {
+		assert_eq!(
+			target("https://matrix.to/#/!room:example.org/$event?via=example.org"),
+			Some(("!room:example.org".to_string(), vec!["example.org".to_string()])),
+		);
+	}
+
+	#[test]
+	fn rejects_other_targets() {
+		assert_eq!(target("https://matrix.to/#/@alice:example.org"), None);
+		assert_eq!(target("room"), None);
+		assert_eq!(target(""), None);
+	}
+}
@@ -1,9 +1,13 @@
-use std::{fs::{self, File}, io::{BufReader, BufWriter}, path::PathBuf, sync::Mutex};
+use std::{fs::{self, File, OpenOptions}, io::{self, BufReader, Write}, path::{Path, PathBuf}, sync::Mutex};
+
+use chrono::Local;
 
 use lazy_static::lazy_static;
+use matrix_sdk::ruma::UserId;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-use crate::matrix::set_error_message;
+use crate::{matrix::set_error_message, vault::{VaultHeader, VaultKey}};
 
 lazy_static! {
     pub static ref SAVING: Mutex<Saving> = Mutex::new(Saving::new());
@@ -11,6 +15,12 @@ lazy_static! {
 
 pub const BUNDLE_ID: &str = "com.iewnfod.matrix.tui";
 const SAVE_FILE_NAME: &str = "saves.json";
+const STORES_DIR_NAME: &str = "stores";
+
+// 存档格式的版本，修改格式时增加并在 MIGRATIONS 中添加迁移
+const SAVE_VERSION: u32 = 2;
+// MIGRATIONS[n] 把版本 n 的存档升级到版本 n + 1
+const MIGRATIONS: [fn(Value) -> Value; SAVE_VERSION as usize] = [migrate_v0, migrate_v1];
 
 #[cfg(target_os = "macos")]
 fn get_save_path() -> PathBuf {
@@ -35,40 +45,172 @@ fn get_save_file_path() -> PathBuf {
 	path
 }
 
+// 每个账号的每个设备使用单独的本地存储，目录名只保留文件名中安全的字符
+pub fn get_store_name(user_id: &str, device_id: &str) -> String {
+	format!("{}-{}", user_id, device_id)
+		.chars()
+		.map(|c| if c.is_ascii_alphanumeric() || matches!(c, '@' | '.' | '-' | '_') { c } else { '_' })
+		.collect()
+}
+
+pub fn get_store_path(store_name: &str) -> PathBuf {
+	let mut path = get_save_path();
+	path.push(STORES_DIR_NAME);
+	path.push(store_name);
+	path
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
-pub struct Saving {
+#[serde(default)]
+pub struct Account {
 	pub token: String,
 	pub username: String,
 	pub server: String,
+	pub homeserver: String,
+	pub user_id: String,
+	pub device_id: String,
+	pub store: String,
+	// 本地加密存储的口令，只在启用保险库时生成
+	pub store_passphrase: String,
+	// 启用保险库时，令牌和存储口令加密后保存在这里
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub secrets: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct Secrets {
+	token: String,
+	store_passphrase: String,
+}
+
+impl Account {
+	// 恢复会话需要完整的用户、设备和服务器信息
+	pub fn has_session(&self) -> bool {
+		!self.token.is_empty()
+			&& !self.homeserver.is_empty()
+			&& !self.user_id.is_empty()
+			&& !self.device_id.is_empty()
+			&& !self.store.is_empty()
+	}
+
+	pub fn get_store_passphrase(&self) -> Option<&str> {
+		if self.store_passphrase.is_empty() {
+			None
+		} else {
+			Some(&self.store_passphrase)
+		}
+	}
+
+	fn seal(&mut self, key: &VaultKey) {
+		let secrets = Secrets {
+			token: std::mem::take(&mut self.token),
+			store_passphrase: std::mem::take(&mut self.store_passphrase),
+		};
+		self.secrets = Some(key.encrypt(&serde_json::to_vec(&secrets).unwrap()));
+	}
+
+	fn open(&mut self, key: &VaultKey) -> bool {
+		let Some(data) = self.secrets.take() else {
+			return true;
+		};
+		let Some(secrets) = key.decrypt(&data).and_then(|plain| serde_json::from_slice::<Secrets>(&plain).ok()) else {
+			self.secrets = Some(data);
+			return false;
+		};
+		self.token = secrets.token;
+		self.store_passphrase = secrets.store_passphrase;
+		true
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Saving {
+	pub version: u32,
+	pub accounts: Vec<Account>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub vault: Option<VaultHeader>,
+	// 解锁后的密钥，不写入存档
+	#[serde(skip)]
+	vault_key: Option<VaultKey>,
 }
 
 impl Saving {
 	pub fn new() -> Self {
-		if let Some(s) = Self::from_saves() {
-			s
+		Self::from_saves().unwrap_or_default()
+	}
+
+	// 按用户 ID 更新或添加账号
+	pub fn upsert_account(&mut self, account: Account) {
+		if let Some(old) = self.accounts.iter_mut().find(|old| old.user_id == account.user_id) {
+			*old = account;
 		} else {
-			Self::default()
+			self.accounts.push(account);
 		}
 	}
 
+	// 启用了保险库但还没有输入口令
+	pub fn is_locked(&self) -> bool {
+		self.vault.is_some() && self.vault_key.is_none()
+	}
+
+	// 用口令派生的密钥解密所有账号的令牌，有账号无法解密时不做任何修改并返回 false
+	pub fn unlock(&mut self, key: VaultKey) -> bool {
+		let mut accounts = self.accounts.clone();
+		if !accounts.iter_mut().all(|account| account.open(&key)) {
+			return false;
+		}
+		self.accounts = accounts;
+		self.vault_key = Some(key);
+		true
+	}
+
+	// 启用保险库或更换口令，并立即重新保存
+	pub fn enable_vault(&mut self, header: VaultHeader, key: VaultKey) {
+		self.vault = Some(header);
+		self.vault_key = Some(key);
+		self.save();
+	}
+
 	fn from_saves() -> Option<Self> {
 		let save_path = get_save_file_path();
 		if !save_path.exists() {
 			return None;
 		}
 
-		let file = File::open(save_path).unwrap();
+		let file = match File::open(&save_path) {
+			Ok(f) => f,
+			Err(e) => {
+				backup_unreadable(&save_path, e);
+				return None;
+			}
+		};
 		let reader = BufReader::new(file);
 
-		let deserialized: Self = match serde_json::from_reader(reader) {
+		let value: Value = match serde_json::from_reader(reader) {
 			Ok(d) => d,
 			Err(e) => {
-				set_error_message(format!("Failed to load savings: {}", e.to_string()));
+				backup_unreadable(&save_path, e);
 				return None;
 			}
 		};
 
-		Some(deserialized)
+		let version = get_save_version(&value);
+		if version > SAVE_VERSION {
+			backup_unreadable(&save_path, format!("unsupported version {}", version));
+			return None;
+		}
+
+		let value = MIGRATIONS[version as usize..].iter().fold(value, |value, migrate| migrate(value));
+
+		match serde_json::from_value(value) {
+			Ok(d) => Some(d),
+			Err(e) => {
+				backup_unreadable(&save_path, e);
+				None
+			}
+		}
 	}
 
 	pub fn save(&self) {
@@ -77,15 +219,182 @@ impl Saving {
 			fs::create_dir_all(p.parent().unwrap()).unwrap();
 		}
 
-		let file = match File::create(p) {
-			Ok(f) => f,
-			Err(e) => {
-				set_error_message(format!("Error creating save file: {}", e));
-				return;
+		// 保险库解锁时写入前加密令牌，未解锁时账号中只有密文
+		let mut saving = self.clone();
+		saving.version = SAVE_VERSION;
+		if let Some(key) = &self.vault_key {
+			for account in saving.accounts.iter_mut() {
+				account.seal(key);
 			}
-		};
-		let writer = BufWriter::new(file);
+		}
+
+		let data = serde_json::to_vec_pretty(&saving).unwrap();
+		if let Err(e) = write_atomic(&p, &data) {
+			set_error_message(format!("Error writing save file: {}", e));
+		}
+	}
+}
+
+// 没有版本号的存档：有 accounts 的是版本 1，否则是最早的单账号格式
+fn get_save_version(value: &Value) -> u32 {
+	match value.get("version").and_then(Value::as_u64) {
+		Some(version) => version.try_into().unwrap_or(u32::MAX),
+		None if value.get("accounts").is_some() => 1,
+		None => 0,
+	}
+}
+
+// 版本 0 只保存了一个账号的 {token, username, server}
+fn migrate_v0(mut value: Value) -> Value {
+	let username = value.get("username").and_then(Value::as_str).unwrap_or_default().to_string();
+	let server = value.get("server").and_then(Value::as_str).unwrap_or_default().to_string();
+	if username.is_empty() || server.is_empty() {
+		return json!({ "accounts": [] });
+	}
+
+	// 没有用户 ID 时按用户名和服务器推测，用户名可能已经是完整的用户 ID，保留账号以便填入登录表单
+	let user_id = match UserId::parse(username.as_str()) {
+		Ok(user_id) => user_id.to_string(),
+		Err(_) => format!("@{}:{}", username, server),
+	};
+	if let Some(account) = value.as_object_mut() {
+		account.entry("user_id").or_insert_with(|| json!(user_id));
+	}
+	json!({ "accounts": [value] })
+}
+
+fn migrate_v1(mut value: Value) -> Value {
+	value["version"] = json!(2);
+	value
+}
+
+// 无法读取的存档改名备份，避免下次保存时覆盖
+fn backup_unreadable<E: std::fmt::Display>(path: &Path, e: E) {
+	let mut backup = path.as_os_str().to_owned();
+	backup.push(format!(".{}.bak", Local::now().format("%Y%m%d%H%M%S")));
+	let backup = PathBuf::from(backup);
+
+	match fs::rename(path, &backup) {
+		Ok(_) => set_error_message(format!("Failed to load savings ({}), backed up to {}", e, backup.display())),
+		Err(rename_error) => set_error_message(format!("Failed to load savings ({}) or back it up: {}", e, rename_error)),
+	}
+}
+
+// 先写入临时文件再改名，写入中断时不会破坏原来的存档
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+	let mut tmp = path.as_os_str().to_owned();
+	tmp.push(".tmp");
+	let tmp = PathBuf::from(tmp);
+
+	let mut file = create_private_file(&tmp)?;
+	file.write_all(data)?;
+	file.sync_all()?;
+	drop(file);
+
+	fs::rename(&tmp, path)
+}
+
+// 存档中包含令牌，只允许当前用户读写
+#[cfg(unix)]
+fn create_private_file(path: &Path) -> io::Result<File> {
+	use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+	let file = OpenOptions::new()
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.mode(0o600)
+		.open(path)?;
+	// 旧版本创建的文件沿用了默认权限
+	file.set_permissions(fs::Permissions::from_mode(0o600))?;
+	Ok(file)
+}
+
+#[cfg(not(unix))]
+fn create_private_file(path: &Path) -> io::Result<File> {
+	OpenOptions::new()
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.open(path)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn migrate(value: Value) -> Saving {
+		let version = get_save_version(&value);
+		let value = MIGRATIONS[version as usize..].iter().fold(value, |value, migrate| migrate(value));
+		serde_json::from_value(value).unwrap()
+	}
+
+	#[test]
+	fn detects_save_versions() {
+		assert_eq!(get_save_version(&json!({ "token": "t", "username": "alice", "server": "example.org" })), 0);
+		assert_eq!(get_save_version(&json!({ "accounts": [] })), 1);
+		assert_eq!(get_save_version(&json!({ "version": 2, "accounts": [] })), 2);
+		assert_eq!(get_save_version(&json!({ "version": u64::MAX })), u32::MAX);
+	}
+
+	#[test]
+	fn migrates_single_account_save() {
+		let saving = migrate(json!({ "token": "secret", "username": "alice", "server": "example.org" }));
+		assert_eq!(saving.version, SAVE_VERSION);
+		assert_eq!(saving.accounts.len(), 1);
+
+		let account = &saving.accounts[0];
+		assert_eq!(account.user_id, "@alice:example.org");
+		assert_eq!(account.username, "alice");
+		assert_eq!(account.server, "example.org");
+		assert_eq!(account.token, "secret");
+		// 旧格式没有设备和存储，需要重新登录
+		assert!(!account.has_session());
+	}
+
+	#[test]
+	fn migrates_single_account_save_with_full_user_id() {
+		let saving = migrate(json!({ "token": "secret", "username": "@alice:example.org", "server": "matrix.example.org" }));
+		assert_eq!(saving.accounts.len(), 1);
+		assert_eq!(saving.accounts[0].user_id, "@alice:example.org");
+		assert_eq!(saving.accounts[0].username, "@alice:example.org");
+	}
+
+	#[test]
+	fn migrates_empty_single_account_save() {
+		let saving = migrate(json!({ "token": "", "username": "", "server": "" }));
+		assert_eq!(saving.version, SAVE_VERSION);
+		assert!(saving.accounts.is_empty());
+	}
+
+	#[test]
+	fn migrates_unversioned_accounts_save() {
+		let saving = migrate(json!({
+			"accounts": [{
+				"token": "secret",
+				"username": "alice",
+				"server": "example.org",
+				"homeserver": "https://matrix.example.org/",
+				"user_id": "@alice:example.org",
+				"device_id": "DEVICE",
+				"store": "@alice_example.org-DEVICE",
+			}]
+		}));
+		assert_eq!(saving.version, SAVE_VERSION);
+		assert_eq!(saving.accounts.len(), 1);
+		assert!(saving.accounts[0].has_session());
+		assert!(saving.vault.is_none());
+	}
+
+	#[test]
+	fn keeps_current_save() {
+		let saving = migrate(json!({ "version": SAVE_VERSION, "accounts": [{ "user_id": "@bob:example.org" }] }));
+		assert_eq!(saving.version, SAVE_VERSION);
+		assert_eq!(saving.accounts[0].user_id, "@bob:example.org");
+	}
 
-		serde_json::to_writer_pretty(writer, self).unwrap();
+	#[test]
+	fn sanitizes_store_names() {
+		assert_eq!(get_store_name("@alice:example.org", "AB/CD"), "@alice_example.org-AB_CD");
 	}
 }
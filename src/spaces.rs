@@ -0,0 +1,376 @@
+use std::collections::{HashMap, HashSet};
+
+use matrix_sdk::{
+	deserialized_responses::SyncOrStrippedState,
+	ruma::{
+		api::client::space::get_hierarchy,
+		events::{
+			space::{child::SpaceChildEventContent, parent::SpaceParentEventContent},
+			SyncStateEvent,
+		},
+		room::RoomType,
+		OwnedRoomId, OwnedServerName, RoomId,
+	},
+	Room, RoomState,
+};
+
+use crate::matrix::{get_client, refresh_rooms, set_error_message, set_open_room, with_session, RoomItem};
+
+// 浏览层级时最多加载的房间数
+const HIERARCHY_LIMIT: usize = 200;
+
+// 空间树中的一行
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeRow {
+	pub room_id: OwnedRoomId,
+	pub depth: usize,
+}
+
+// 通过 /hierarchy 浏览到的房间
+#[derive(Debug, Clone)]
+pub struct HierarchyRoom {
+	pub room_id: OwnedRoomId,
+	pub name: String,
+	pub topic: Option<String>,
+	pub members: u64,
+	pub is_space: bool,
+	pub joined: bool,
+	pub depth: usize,
+	// 加入时使用的服务器
+	pub via: Vec<OwnedServerName>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Hierarchy {
+	pub space_id: OwnedRoomId,
+	pub rooms: Vec<HierarchyRoom>,
+	pub loading: bool,
+}
+
+// 房间状态中的 m.space.child，via 为空表示已被移除
+pub async fn space_children(room: &Room) -> Vec<OwnedRoomId> {
+	let Ok(events) = room.get_state_events_static::<SpaceChildEventContent>().await else {
+		return Vec::new();
+	};
+
+	events.iter().filter_map(|raw| match raw.deserialize().ok()? {
+		SyncOrStrippedState::Sync(SyncStateEvent::Original(ev)) if !ev.content.via.is_empty() => Some(ev.state_key),
+		_ => None,
+	}).collect()
+}
+
+// 房间状态中的 m.space.parent
+pub async fn space_parents(room: &Room) -> Vec<OwnedRoomId> {
+	let Ok(events) = room.get_state_events_static::<SpaceParentEventContent>().await else {
+		return Vec::new();
+	};
+
+	events.iter().filter_map(|raw| match raw.deserialize().ok()? {
+		SyncOrStrippedState::Sync(SyncStateEvent::Original(ev)) if !ev.content.via.is_empty() => Some(ev.state_key),
+		_ => None,
+	}).collect()
+}
+
+// 按空间关系把房间列表展开成树，没有父空间的房间放在根部
+pub fn build_tree(rooms: &[RoomItem], filter: Option<&RoomId>, collapsed: &HashSet<OwnedRoomId>) -> Vec<TreeRow> {
+	let rooms: Vec<&RoomItem> = rooms.iter().filter(|room| !room.direct).collect();
+	let joined: HashSet<&RoomId> = rooms.iter().map(|room| &*room.room_id).collect();
+
+	// 子房间可以由空间的 m.space.child 或房间自己的 m.space.parent 声明
+	let mut children: HashMap<&RoomId, Vec<&RoomItem>> = HashMap::new();
+	for space in rooms.iter().filter(|room| room.is_space) {
+		let list = rooms.iter()
+			.filter(|room| room.room_id != space.room_id)
+			.filter(|room| space.children.contains(&room.room_id) || room.parents.contains(&space.room_id))
+			.copied();
+		// 空间排在房间前面
+		let (mut list, others): (Vec<&RoomItem>, Vec<&RoomItem>) = list.partition(|room| room.is_space);
+		list.extend(others);
+		children.insert(&*space.room_id, list);
+	}
+
+	let roots: Vec<&RoomItem> = match filter {
+		Some(space_id) if joined.contains(space_id) => children.get(space_id).cloned().unwrap_or_default(),
+		_ => {
+			let has_parent: HashSet<&RoomId> = children.values()
+				.flat_map(|list| list.iter().map(|room| &*room.room_id))
+				.collect();
+			let (mut roots, others): (Vec<&RoomItem>, Vec<&RoomItem>) = rooms.iter()
+				.filter(|room| !has_parent.contains(&*room.room_id))
+				.partition(|room| room.is_space);
+			roots.extend(others);
+
+			// 互相包含的空间都有父空间，从根部到达不了时把其中第一个作为根
+			let mut reachable = HashSet::new();
+			for room in &roots {
+				mark_reachable(room, &children, &mut reachable);
+			}
+			for room in &rooms {
+				if !reachable.contains(&*room.room_id) {
+					mark_reachable(room, &children, &mut reachable);
+					roots.push(room);
+				}
+			}
+			roots
+		}
+	};
+
+	let mut rows = Vec::new();
+	let mut path = Vec::new();
+	for room in roots {
+		push_rows(room, 0, &children, collapsed, &mut path, &mut rows);
+	}
+	rows
+}
+
+fn mark_reachable<'a>(room: &'a RoomItem, children: &HashMap<&RoomId, Vec<&'a RoomItem>>, reachable: &mut HashSet<&'a RoomId>) {
+	if !reachable.insert(&*room.room_id) {
+		return;
+	}
+	for child in children.get(&*room.room_id).into_iter().flatten() {
+		mark_reachable(child, children, reachable);
+	}
+}
+
+fn push_rows<'a>(
+	room: &'a RoomItem,
+	depth: usize,
+	children: &HashMap<&RoomId, Vec<&'a RoomItem>>,
+	collapsed: &HashSet<OwnedRoomId>,
+	path: &mut Vec<&'a RoomId>,
+	rows: &mut Vec<TreeRow>,
+) {
+	// 空间之间可能互相包含，跳过已经在路径上的空间
+	if path.contains(&&*room.room_id) {
+		return;
+	}
+
+	rows.push(TreeRow {
+		room_id: room.room_id.clone(),
+		depth,
+	});
+
+	if !room.is_space || collapsed.contains(&room.room_id) {
+		return;
+	}
+
+	path.push(&*room.room_id);
+	for child in children.get(&*room.room_id).into_iter().flatten() {
+		push_rows(child, depth + 1, children, collapsed, path, rows);
+	}
+	path.pop();
+}
+
+fn set_hierarchy(hierarchy: Option<Hierarchy>) {
+	let Some(client) = get_client() else {
+		return;
+	};
+	if let Some(user_id) = client.user_id() {
+		with_session(user_id, |session| session.hierarchy = hierarchy);
+	}
+}
+
+// 通过 /hierarchy 获取空间中的所有房间，包括还没有加入的
+pub async fn browse_hierarchy(space_id: OwnedRoomId) {
+	let Some(client) = get_client() else {
+		return;
+	};
+
+	set_error_message("");
+	set_hierarchy(Some(Hierarchy {
+		space_id: space_id.clone(),
+		rooms: Vec::new(),
+		loading: true,
+	}));
+
+	let mut rooms: Vec<HierarchyRoom> = Vec::new();
+	let mut depths: HashMap<OwnedRoomId, usize> = HashMap::new();
+	let mut vias: HashMap<OwnedRoomId, Vec<OwnedServerName>> = HashMap::new();
+	let mut from = None;
+	loop {
+		let mut request = get_hierarchy::v1::Request::new(space_id.clone());
+		request.from = from.take();
+
+		let response = match client.send(request, None).await {
+			Ok(response) => response,
+			Err(e) => {
+				set_error_message(format!("Failed to browse space: {}", e));
+				break;
+			}
+		};
+
+		for chunk in response.rooms {
+			let depth = depths.get(&chunk.room_id).copied().unwrap_or_default();
+			for child in chunk.children_state.iter().filter_map(|raw| raw.deserialize().ok()) {
+				let Ok(child_id) = OwnedRoomId::try_from(child.state_key) else {
+					continue;
+				};
+				depths.entry(child_id.clone()).or_insert(depth + 1);
+				vias.entry(child_id).or_insert(child.content.via);
+			}
+
+			let joined = client.get_room(&chunk.room_id)
+				.is_some_and(|room| room.state() == RoomState::Joined);
+			let name = chunk.name
+				.or_else(|| chunk.canonical_alias.map(|alias| alias.to_string()))
+				.unwrap_or_else(|| chunk.room_id.to_string());
+			rooms.push(HierarchyRoom {
+				via: vias.get(&chunk.room_id).cloned().unwrap_or_default(),
+				room_id: chunk.room_id,
+				name,
+				topic: chunk.topic,
+				members: chunk.num_joined_members.into(),
+				is_space: chunk.room_type == Some(RoomType::Space),
+				joined,
+				depth,
+			});
+		}
+
+		from = response.next_batch;
+		if from.is_none() || rooms.len() >= HIERARCHY_LIMIT {
+			break;
+		}
+	}
+
+	set_hierarchy(Some(Hierarchy {
+		space_id,
+		rooms,
+		loading: false,
+	}));
+}
+
+// 加入层级中列出的房间，普通房间加入后直接打开
+pub async fn join_hierarchy_room(room: HierarchyRoom) {
+	let Some(client) = get_client() else {
+		return;
+	};
+
+	set_error_message("");
+	if let Err(e) = client.join_room_by_id_or_alias((&*room.room_id).into(), &room.via).await {
+		set_error_message(format!("Failed to join room: {}", e));
+		return;
+	}
+
+	if let Some(user_id) = client.user_id() {
+		with_session(user_id, |session| {
+			let joined = session.hierarchy.iter_mut()
+				.flat_map(|hierarchy| hierarchy.rooms.iter_mut())
+				.filter(|item| item.room_id == room.room_id);
+			for item in joined {
+				item.joined = true;
+			}
+		});
+	}
+
+	refresh_rooms(&client).await;
+	if !room.is_space {
+		set_open_room(room.room_id);
+	}
+}
+
+pub fn close_hierarchy() {
+	set_hierarchy(None);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn room_id(id: &str) -> OwnedRoomId {
+		OwnedRoomId::try_from(format!("!{}:example.org", id)).unwrap()
+	}
+
+	fn room(id: &str, is_space: bool, children: &[&str], parents: &[&str]) -> RoomItem {
+		RoomItem {
+			room_id: room_id(id),
+			name: id.to_string(),
+			unread: 0,
+			highlight: 0,
+			encrypted: false,
+			direct: false,
+			is_space,
+			children: children.iter().map(|id| room_id(id)).collect(),
+			parents: parents.iter().map(|id| room_id(id)).collect(),
+		}
+	}
+
+	fn rows(rooms: &[RoomItem], filter: Option<&str>, collapsed: &[&str]) -> Vec<(String, usize)> {
+		let filter = filter.map(room_id);
+		let collapsed = collapsed.iter().map(|id| room_id(id)).collect();
+		build_tree(rooms, filter.as_deref(), &collapsed).into_iter()
+			.map(|row| (row.room_id.as_str()[1..].trim_end_matches(":example.org").to_string(), row.depth))
+			.collect()
+	}
+
+	fn expected(rows: &[(&str, usize)]) -> Vec<(String, usize)> {
+		rows.iter().map(|(id, depth)| (id.to_string(), *depth)).collect()
+	}
+
+	#[test]
+	fn nests_rooms_under_spaces() {
+		let rooms = vec![
+			room("lobby", false, &[], &[]),
+			room("space", true, &["general"], &[]),
+			room("general", false, &[], &[]),
+			// 只由房间自己声明的父空间
+			room("random", false, &[], &["space"]),
+			room("sub", true, &[], &["space"]),
+		];
+		assert_eq!(rows(&rooms, None, &[]), expected(&[
+			("space", 0),
+			("sub", 1),
+			("general", 1),
+			("random", 1),
+			("lobby", 0),
+		]));
+	}
+
+	#[test]
+	fn hides_direct_messages() {
+		let mut dm = room("dm", false, &[], &[]);
+		dm.direct = true;
+		assert!(rows(&[dm], None, &[]).is_empty());
+	}
+
+	#[test]
+	fn collapses_spaces() {
+		let rooms = vec![
+			room("space", true, &["general"], &[]),
+			room("general", false, &[], &[]),
+		];
+		assert_eq!(rows(&rooms, None, &["space"]), expected(&[("space", 0)]));
+	}
+
+	#[test]
+	fn filters_by_space() {
+		let rooms = vec![
+			room("space", true, &["general"], &[]),
+			room("general", false, &[], &[]),
+			room("lobby", false, &[], &[]),
+		];
+		assert_eq!(rows(&rooms, Some("space"), &[]), expected(&[("general", 0)]));
+		// 没有加入的空间不筛选
+		assert_eq!(rows(&rooms, Some("unknown"), &[]).len(), 3);
+	}
+
+	#[test]
+	fn shows_spaces_that_contain_each_other() {
+		let rooms = vec![
+			room("a", true, &["b"], &[]),
+			room("b", true, &["a"], &[]),
+		];
+		assert_eq!(rows(&rooms, None, &[]), expected(&[("a", 0), ("b", 1)]));
+	}
+
+	#[test]
+	fn stops_at_cycles_below_a_root() {
+		let rooms = vec![
+			room("root", true, &["a"], &[]),
+			room("a", true, &["b"], &[]),
+			room("b", true, &["a"], &[]),
+		];
+		assert_eq!(rows(&rooms, None, &[]), expected(&[("root", 0), ("a", 1), ("b", 2)]));
+		// 折叠的空间中的子空间不会被当作根
+		assert_eq!(rows(&rooms, None, &["root"]), expected(&[("root", 0)]));
+	}
+}
@@ -0,0 +1,1386 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Local};
+use matrix_sdk::{
+	crypto::MegolmError,
+	deserialized_responses::TimelineEvent,
+	room::MessagesOptions,
+	ruma::{
+		api::client::{context::get_context, relations::get_relating_events_with_rel_type},
+		events::{
+			reaction::{OriginalSyncReactionEvent, ReactionEventContent},
+			relation::{Annotation, RelationType, Thread},
+			room::{
+				encrypted::{self, OriginalSyncRoomEncryptedEvent},
+				message::{
+					self, sanitize::remove_plain_reply_fallback, AddMentions, ForwardThread, MessageType,
+					OriginalSyncRoomMessageEvent, ReplacementMetadata, RoomMessageEventContent, SyncRoomMessageEvent,
+				},
+				redaction::OriginalSyncRoomRedactionEvent,
+			},
+			AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+		},
+		serde::Raw,
+		MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedTransactionId, OwnedUserId, TransactionId, UInt, UserId,
+	},
+	Client, Room,
+};
+
+use crate::matrix::{get_room, set_error_message, with_session};
+
+// 跳转到不在已加载范围内的消息时，同时加载它前后的消息数
+const JUMP_CONTEXT_LIMIT: u32 = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendState {
+	Sent,
+	Sending,
+	Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TimelineItem {
+	// 本地回显的消息在发送成功前没有 event id
+	pub event_id: Option<OwnedEventId>,
+	pub txn_id: Option<OwnedTransactionId>,
+	pub sender_id: OwnedUserId,
+	pub sender: String,
+	pub timestamp: MilliSecondsSinceUnixEpoch,
+	pub body: String,
+	pub send_state: SendState,
+	// 无法解密时的原因
+	pub decryption_error: Option<String>,
+	// 属于某个话题时为话题根消息的 event id
+	pub thread_root: Option<OwnedEventId>,
+	// 作为话题根消息时的回复数
+	pub thread_count: u64,
+	// 回复的消息
+	pub in_reply_to: Option<OwnedEventId>,
+	// 内容已被编辑过
+	pub edited: bool,
+	// 已被删除
+	pub redacted: bool,
+	// 作为编辑时被替换的消息
+	replaces: Option<OwnedEventId>,
+	// 可以编辑的消息的原始内容，不能编辑的类型为空
+	msgtype: Option<MessageType>,
+	// 保留无法解密的原始事件，收到密钥后重试
+	encrypted: Option<Raw<OriginalSyncRoomEncryptedEvent>>,
+}
+
+impl TimelineItem {
+	pub fn time(&self) -> String {
+		let millis = i64::from(self.timestamp.get());
+		match DateTime::from_timestamp_millis(millis) {
+			Some(time) => time.with_timezone(&Local).format("%m-%d %H:%M").to_string(),
+			None => String::new(),
+		}
+	}
+}
+
+// 对消息的一个回应
+#[derive(Debug, Clone)]
+struct Reaction {
+	// 本地回显的回应在发送成功前没有 event id
+	event_id: Option<OwnedEventId>,
+	txn_id: Option<OwnedTransactionId>,
+	key: String,
+	// 是否是自己的回应
+	own: bool,
+}
+
+// 消息下方按表情聚合的回应
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReactionGroup {
+	pub key: String,
+	pub count: usize,
+	pub own: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Timeline {
+	pub items: Vec<TimelineItem>,
+	// `/messages` 向前翻页的起始 token，为空时从最新的消息开始
+	pub prev_batch: Option<String>,
+	pub paginating: bool,
+	pub reached_start: bool,
+	// 侧边栏中打开的话题
+	pub open_thread: Option<OwnedEventId>,
+	pub thread_loading: bool,
+	// 每个话题中未读的新回复数
+	pub thread_unread: HashMap<OwnedEventId, u64>,
+	// 被回复但不在已加载范围内的消息，用于显示引用
+	pub quoted: HashMap<OwnedEventId, TimelineItem>,
+	// 加载完成后需要跳转到的消息
+	jump_to: Option<OwnedEventId>,
+	// 每条消息最新的编辑
+	edits: HashMap<OwnedEventId, TimelineItem>,
+	// 每条消息收到的回应
+	reactions: HashMap<OwnedEventId, Vec<Reaction>>,
+	// 无法解密的回应，收到密钥后重试
+	pending_reactions: Vec<Raw<OriginalSyncRoomEncryptedEvent>>,
+}
+
+impl Timeline {
+	fn contains(&self, event_id: &OwnedEventId) -> bool {
+		self.items.iter().any(|item| item.event_id.as_ref() == Some(event_id))
+	}
+
+	// 已加载或被引用的消息
+	pub fn find(&self, event_id: &OwnedEventId) -> Option<&TimelineItem> {
+		self.items.iter()
+			.find(|item| item.event_id.as_ref() == Some(event_id))
+			.or_else(|| self.quoted.get(event_id))
+	}
+
+	fn find_local_echo(&mut self, txn_id: &TransactionId) -> Option<&mut TimelineItem> {
+		self.items.iter_mut().find(|item| item.txn_id.as_deref() == Some(txn_id))
+	}
+
+	// 加入一条消息，返回是否是新消息
+	fn insert(&mut self, item: TimelineItem) -> bool {
+		// 编辑不单独显示，只替换原消息的内容
+		if item.replaces.is_some() {
+			self.add_edit(item);
+			return false;
+		}
+
+		if let Some(event_id) = &item.event_id {
+			if self.contains(event_id) {
+				return false;
+			}
+		}
+
+		// 服务器返回的事件替换掉本地回显
+		if let Some(txn_id) = item.txn_id.clone() {
+			if let Some(local_echo) = self.find_local_echo(&txn_id) {
+				*local_echo = item;
+				return false;
+			}
+		}
+
+		// 话题中的消息可能早于已加载的消息，按时间插入
+		let index = self.items.iter()
+			.rposition(|other| other.timestamp <= item.timestamp)
+			.map_or(0, |index| index + 1);
+		let event_id = item.event_id.clone();
+		self.items.insert(index, item);
+		// 编辑可能比原消息先加载
+		if let Some(event_id) = event_id {
+			self.apply_edit(&event_id);
+		}
+		true
+	}
+
+	// 加入被引用的消息
+	fn quote(&mut self, event_id: OwnedEventId, item: TimelineItem) {
+		self.quoted.insert(event_id.clone(), item);
+		self.apply_edit(&event_id);
+	}
+
+	// 记录一条编辑，同一条消息只保留最新的编辑
+	fn add_edit(&mut self, edit: TimelineItem) {
+		let Some(target) = edit.replaces.clone() else {
+			return;
+		};
+
+		let newer = self.edits.get(&target).map_or(true, |old| old.timestamp <= edit.timestamp);
+		if newer {
+			self.edits.insert(target.clone(), edit);
+		}
+		self.apply_edit(&target);
+	}
+
+	// 用最新的编辑替换原消息的内容，只接受原发送者的编辑
+	fn apply_edit(&mut self, target: &OwnedEventId) {
+		let Some(edit) = self.edits.get(target) else {
+			return;
+		};
+		if edit.decryption_error.is_some() {
+			return;
+		}
+
+		let originals = self.items.iter_mut()
+			.filter(|item| item.event_id.as_ref() == Some(target))
+			.chain(self.quoted.get_mut(target));
+		for item in originals {
+			if item.sender_id == edit.sender_id && !item.redacted {
+				item.body = edit.body.clone();
+				item.msgtype = edit.msgtype.clone();
+				item.edited = true;
+			}
+		}
+	}
+
+	// 按表情聚合消息的回应，按第一次出现的顺序排列
+	pub fn reactions(&self, event_id: &OwnedEventId) -> Vec<ReactionGroup> {
+		let mut groups: Vec<ReactionGroup> = Vec::new();
+		for reaction in self.reactions.get(event_id).into_iter().flatten() {
+			match groups.iter_mut().find(|group| group.key == reaction.key) {
+				Some(group) => {
+					group.count += 1;
+					group.own |= reaction.own;
+				},
+				None => groups.push(ReactionGroup {
+					key: reaction.key.clone(),
+					count: 1,
+					own: reaction.own,
+				}),
+			}
+		}
+		groups
+	}
+
+	fn add_reaction(&mut self, target: OwnedEventId, reaction: Reaction) {
+		let reactions = self.reactions.entry(target).or_default();
+		if reaction.event_id.is_some() && reactions.iter().any(|other| other.event_id == reaction.event_id) {
+			return;
+		}
+
+		// 服务器返回的回应替换掉本地回显
+		if let Some(txn_id) = &reaction.txn_id {
+			if let Some(local_echo) = reactions.iter_mut().find(|other| other.txn_id.as_ref() == Some(txn_id)) {
+				*local_echo = reaction;
+				return;
+			}
+		}
+		reactions.push(reaction);
+	}
+
+	// 消息被删除后清空内容
+	fn redact(&mut self, event_id: &OwnedEventId) {
+		self.edits.remove(event_id);
+		// 被删除的也可能是回应
+		self.reactions.remove(event_id);
+		for reactions in self.reactions.values_mut() {
+			reactions.retain(|reaction| reaction.event_id.as_ref() != Some(event_id));
+		}
+		let redacted = self.items.iter_mut()
+			.filter(|item| item.event_id.as_ref() == Some(event_id))
+			.chain(self.quoted.get_mut(event_id));
+		for item in redacted {
+			item.body.clear();
+			item.msgtype = None;
+			item.redacted = true;
+			item.edited = false;
+			item.decryption_error = None;
+			item.encrypted = None;
+		}
+	}
+
+	// 解密后不是消息的事件（贴纸、状态事件等）不再显示占位消息
+	fn remove_placeholder(&mut self, event_id: &OwnedEventId) {
+		let is_placeholder = |item: &TimelineItem| item.event_id.as_ref() == Some(event_id) && item.decryption_error.is_some();
+		self.edits.retain(|_, edit| !is_placeholder(edit));
+
+		let roots: Vec<OwnedEventId> = self.items.iter()
+			.filter(|item| is_placeholder(item))
+			.filter_map(|item| item.thread_root.clone())
+			.collect();
+		self.items.retain(|item| !is_placeholder(item));
+		for root in roots {
+			if let Some(root) = self.items.iter_mut().find(|item| item.event_id.as_ref() == Some(&root)) {
+				root.thread_count = root.thread_count.saturating_sub(1);
+			}
+		}
+	}
+
+	// 加入新收到的消息，话题回复会增加根消息的回复数
+	fn push(&mut self, item: TimelineItem) -> bool {
+		let thread_root = item.thread_root.clone();
+		if !self.insert(item) {
+			return false;
+		}
+
+		if let Some(root) = thread_root {
+			if let Some(root) = self.items.iter_mut().find(|item| item.event_id.as_ref() == Some(&root)) {
+				root.thread_count += 1;
+			}
+		}
+		true
+	}
+
+	// 话题中的消息，按时间排序，根消息在最前面
+	pub fn thread_items(&self, root: &OwnedEventId) -> Vec<&TimelineItem> {
+		let mut items: Vec<&TimelineItem> = self.items.iter()
+			.filter(|item| item.thread_root.as_ref() == Some(root))
+			.collect();
+		items.sort_by_key(|item| item.timestamp);
+		if let Some(root) = self.items.iter().find(|item| item.event_id.as_ref() == Some(root)) {
+			items.insert(0, root);
+		}
+		items
+	}
+}
+
+fn message_body(msgtype: &MessageType, reply: bool) -> String {
+	// 回复的正文开头是引用原消息的回退内容
+	let strip = |body: &str| if reply {
+		remove_plain_reply_fallback(body).to_string()
+	} else {
+		body.to_string()
+	};
+
+	match msgtype {
+		MessageType::Text(text) => strip(&text.body),
+		MessageType::Notice(notice) => strip(&notice.body),
+		MessageType::Emote(emote) => format!("* {}", strip(&emote.body)),
+		MessageType::Image(image) => format!("[image] {}", image.body),
+		MessageType::File(file) => format!("[file] {}", file.body),
+		MessageType::Audio(audio) => format!("[audio] {}", audio.body),
+		MessageType::Video(video) => format!("[video] {}", video.body),
+		other => other.body().to_string(),
+	}
+}
+
+// 用新的正文替换消息内容，只有文本、通知和动作消息可以编辑
+fn edited_msgtype(msgtype: &MessageType, body: String) -> Option<MessageType> {
+	match msgtype {
+		MessageType::Text(_) => Some(MessageType::text_plain(body)),
+		MessageType::Notice(_) => Some(MessageType::notice_plain(body)),
+		MessageType::Emote(_) => Some(MessageType::emote_plain(body)),
+		_ => None,
+	}
+}
+
+// 可以编辑的消息的原始内容，去掉回复的回退内容
+fn editable_msgtype(msgtype: &MessageType, reply: bool) -> Option<MessageType> {
+	let body = if reply {
+		remove_plain_reply_fallback(msgtype.body()).to_string()
+	} else {
+		msgtype.body().to_string()
+	};
+	edited_msgtype(msgtype, body)
+}
+
+async fn sender_name(room: &Room, sender: &UserId) -> String {
+	match room.get_member_no_sync(sender).await {
+		Ok(Some(member)) => member.name().to_string(),
+		_ => sender.to_string(),
+	}
+}
+
+// 从消息关系中取出话题根消息和回复的消息
+fn message_relations<C>(relation: Option<&message::Relation<C>>) -> (Option<OwnedEventId>, Option<OwnedEventId>) {
+	match relation {
+		Some(message::Relation::Reply { in_reply_to }) => (None, Some(in_reply_to.event_id.clone())),
+		Some(message::Relation::Thread(thread)) => {
+			// 话题中用于回退的回复关系不是真正的回复
+			let in_reply_to = thread.in_reply_to.as_ref()
+				.filter(|_| !thread.is_falling_back)
+				.map(|in_reply_to| in_reply_to.event_id.clone());
+			(Some(thread.event_id.clone()), in_reply_to)
+		},
+		_ => (None, None),
+	}
+}
+
+async fn to_timeline_item(room: &Room, ev: &OriginalSyncRoomMessageEvent) -> TimelineItem {
+	let (thread_root, in_reply_to) = message_relations(ev.content.relates_to.as_ref());
+	let mut body = message_body(&ev.content.msgtype, in_reply_to.is_some());
+	let mut msgtype = editable_msgtype(&ev.content.msgtype, in_reply_to.is_some());
+	let mut replaces = None;
+	let mut edited = false;
+	match &ev.content.relates_to {
+		// 编辑的新内容在 m.new_content 中
+		Some(message::Relation::Replacement(replacement)) => {
+			body = message_body(&replacement.new_content.msgtype, false);
+			msgtype = editable_msgtype(&replacement.new_content.msgtype, false);
+			replaces = Some(replacement.event_id.clone());
+		},
+		_ => {
+			// 服务器聚合的最新编辑
+			let replacement = ev.unsigned.relations.replace.as_ref().and_then(|edit| match &edit.content.relates_to {
+				Some(message::Relation::Replacement(replacement)) if edit.sender == ev.sender => Some(replacement),
+				_ => None,
+			});
+			if let Some(replacement) = replacement {
+				body = message_body(&replacement.new_content.msgtype, false);
+				msgtype = editable_msgtype(&replacement.new_content.msgtype, false);
+				edited = true;
+			}
+		}
+	}
+
+	TimelineItem {
+		event_id: Some(ev.event_id.clone()),
+		txn_id: ev.unsigned.transaction_id.clone(),
+		sender_id: ev.sender.clone(),
+		sender: sender_name(room, &ev.sender).await,
+		timestamp: ev.origin_server_ts,
+		body,
+		send_state: SendState::Sent,
+		decryption_error: None,
+		thread_root,
+		in_reply_to,
+		// 服务器聚合的话题回复数
+		thread_count: ev.unsigned.relations.thread.as_ref().map(|thread| thread.count.into()).unwrap_or_default(),
+		edited,
+		redacted: false,
+		replaces,
+		msgtype,
+		encrypted: None,
+	}
+}
+
+fn decryption_error_reason(e: &matrix_sdk::Error) -> String {
+	match e {
+		matrix_sdk::Error::MegolmError(MegolmError::MissingRoomKey(Some(code))) => {
+			format!("the sender withheld the room key ({})", code)
+		},
+		matrix_sdk::Error::MegolmError(MegolmError::MissingRoomKey(None)) => {
+			"the room key has not been received".to_string()
+		},
+		e => e.to_string(),
+	}
+}
+
+fn decrypted_message(ev: &TimelineEvent) -> Option<OriginalSyncRoomMessageEvent> {
+	match ev.event.cast_ref::<AnySyncTimelineEvent>().deserialize().ok()? {
+		AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(SyncMessageLikeEvent::Original(ev))) => Some(ev),
+		_ => None,
+	}
+}
+
+// 尝试解密事件，失败时生成带有原因的占位消息
+async fn decrypt_item(room: &Room, raw: Raw<OriginalSyncRoomEncryptedEvent>) -> Option<TimelineItem> {
+	match room.decrypt_event(&raw).await {
+		Ok(ev) => {
+			let ev = decrypted_message(&ev)?;
+			Some(to_timeline_item(room, &ev).await)
+		},
+		Err(e) => {
+			let ev = raw.deserialize().ok()?;
+			// 回应不显示为消息，由 handle_encrypted_reaction 处理
+			if matches!(ev.content.relates_to, Some(encrypted::Relation::Annotation(_))) {
+				return None;
+			}
+			// 消息关系在加密事件中是明文
+			let (thread_root, in_reply_to) = match &ev.content.relates_to {
+				Some(encrypted::Relation::Reply { in_reply_to }) => (None, Some(in_reply_to.event_id.clone())),
+				Some(encrypted::Relation::Thread(thread)) => (Some(thread.event_id.clone()), None),
+				_ => (None, None),
+			};
+			let replaces = match &ev.content.relates_to {
+				Some(encrypted::Relation::Replacement(replacement)) => Some(replacement.event_id.clone()),
+				_ => None,
+			};
+			Some(TimelineItem {
+				event_id: Some(ev.event_id.clone()),
+				txn_id: ev.unsigned.transaction_id.clone(),
+				sender_id: ev.sender.clone(),
+				sender: sender_name(room, &ev.sender).await,
+				timestamp: ev.origin_server_ts,
+				body: String::new(),
+				send_state: SendState::Sent,
+				decryption_error: Some(decryption_error_reason(&e)),
+				thread_root,
+				thread_count: 0,
+				in_reply_to,
+				edited: false,
+				redacted: false,
+				replaces,
+				msgtype: None,
+				encrypted: Some(raw),
+			})
+		}
+	}
+}
+
+fn to_reaction(room: &Room, ev: &OriginalSyncReactionEvent) -> (OwnedEventId, Reaction) {
+	(ev.content.relates_to.event_id.clone(), Reaction {
+		event_id: Some(ev.event_id.clone()),
+		txn_id: ev.unsigned.transaction_id.clone(),
+		key: ev.content.relates_to.key.clone(),
+		own: ev.sender == room.own_user_id(),
+	})
+}
+
+// 历史消息中的回应
+fn reaction_event(room: &Room, ev: &TimelineEvent) -> Option<(OwnedEventId, Reaction)> {
+	match ev.event.cast_ref::<AnySyncTimelineEvent>().deserialize().ok()? {
+		AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::Reaction(SyncMessageLikeEvent::Original(ev))) => {
+			Some(to_reaction(room, &ev))
+		},
+		_ => None,
+	}
+}
+
+// 加密的回应，关系在加密事件中是明文
+fn is_encrypted_reaction(raw: &Raw<OriginalSyncRoomEncryptedEvent>) -> bool {
+	raw.deserialize().is_ok_and(|ev| matches!(ev.content.relates_to, Some(encrypted::Relation::Annotation(_))))
+}
+
+// 历史消息中未能解密的回应
+fn encrypted_reaction_event(ev: &TimelineEvent) -> Option<Raw<OriginalSyncRoomEncryptedEvent>> {
+	match ev.event.cast_ref::<AnySyncTimelineEvent>().deserialize().ok()? {
+		AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomEncrypted(SyncMessageLikeEvent::Original(_))) => {
+			let raw = ev.event.clone().cast();
+			is_encrypted_reaction(&raw).then_some(raw)
+		},
+		_ => None,
+	}
+}
+
+// 解密回应并计入消息，失败时保留原始事件等待重试
+async fn handle_encrypted_reaction(room: &Room, raw: Raw<OriginalSyncRoomEncryptedEvent>) {
+	let reaction = match room.decrypt_event(&raw).await {
+		Ok(ev) => reaction_event(room, &ev),
+		Err(_) => None,
+	};
+	with_timeline(room, |timeline| match reaction {
+		Some((target, reaction)) => timeline.add_reaction(target, reaction),
+		None => timeline.pending_reactions.push(raw),
+	});
+}
+
+// 已被删除的消息
+async fn redacted_item(room: &Room, event_id: OwnedEventId, sender_id: OwnedUserId, timestamp: MilliSecondsSinceUnixEpoch) -> TimelineItem {
+	TimelineItem {
+		event_id: Some(event_id),
+		txn_id: None,
+		sender: sender_name(room, &sender_id).await,
+		sender_id,
+		timestamp,
+		body: String::new(),
+		send_state: SendState::Sent,
+		decryption_error: None,
+		thread_root: None,
+		thread_count: 0,
+		in_reply_to: None,
+		edited: false,
+		redacted: true,
+		replaces: None,
+		msgtype: None,
+		encrypted: None,
+	}
+}
+
+async fn timeline_event_item(room: &Room, ev: TimelineEvent) -> Option<TimelineItem> {
+	match ev.event.cast_ref::<AnySyncTimelineEvent>().deserialize().ok()? {
+		AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(SyncMessageLikeEvent::Original(ev))) => {
+			Some(to_timeline_item(room, &ev).await)
+		},
+		AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomEncrypted(SyncMessageLikeEvent::Original(_))) => {
+			decrypt_item(room, ev.event.cast()).await
+		},
+		AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(SyncMessageLikeEvent::Redacted(ev))) => {
+			Some(redacted_item(room, ev.event_id, ev.sender, ev.origin_server_ts).await)
+		},
+		// 被删除的加密事件无法知道原来是消息、回应还是编辑，不显示
+		_ => None,
+	}
+}
+
+// 修改 room 所属账号中该房间的时间线
+fn with_timeline<R>(room: &Room, f: impl FnOnce(&mut Timeline) -> R) -> Option<R> {
+	with_session(room.own_user_id(), |session| {
+		f(session.timelines.entry(room.room_id().to_owned()).or_default())
+	})
+}
+
+// 加入同步收到的消息，别人在未打开的话题中的回复记为未读
+fn push_synced(room: &Room, item: TimelineItem) {
+	let own = item.sender_id == room.own_user_id();
+	let thread_root = item.thread_root.clone();
+	with_timeline(room, |timeline| {
+		if !timeline.push(item) || own {
+			return;
+		}
+		if let Some(root) = thread_root {
+			if timeline.open_thread.as_ref() != Some(&root) {
+				*timeline.thread_unread.entry(root).or_default() += 1;
+			}
+		}
+	});
+}
+
+// 获取不在已加载范围内的被回复消息，用于显示引用
+async fn fetch_reply_parents(room: &Room, parents: Vec<OwnedEventId>) {
+	for event_id in parents {
+		let loaded = with_timeline(room, |timeline| timeline.find(&event_id).is_some()).unwrap_or_default();
+		if loaded {
+			continue;
+		}
+		let Ok(ev) = room.event(&event_id).await else {
+			continue;
+		};
+		if let Some(item) = timeline_event_item(room, ev).await {
+			with_timeline(room, |timeline| timeline.quote(event_id, item));
+		}
+	}
+}
+
+pub async fn handle_sync_message(ev: SyncRoomMessageEvent, room: Room) {
+	let ev = match ev {
+		SyncRoomMessageEvent::Original(ev) => ev,
+		SyncRoomMessageEvent::Redacted(ev) => {
+			let item = redacted_item(&room, ev.event_id, ev.sender, ev.origin_server_ts).await;
+			push_synced(&room, item);
+			return;
+		}
+	};
+
+	let item = to_timeline_item(&room, &ev).await;
+	let parent = item.in_reply_to.clone();
+	push_synced(&room, item);
+	fetch_reply_parents(&room, parent.into_iter().collect()).await;
+}
+
+// 同步时 SDK 未能解密的消息
+pub async fn handle_sync_encrypted(ev: Raw<OriginalSyncRoomEncryptedEvent>, room: Room) {
+	if is_encrypted_reaction(&ev) {
+		handle_encrypted_reaction(&room, ev).await;
+		return;
+	}
+
+	let Some(item) = decrypt_item(&room, ev).await else {
+		return;
+	};
+
+	let parent = item.in_reply_to.clone();
+	push_synced(&room, item);
+	fetch_reply_parents(&room, parent.into_iter().collect()).await;
+}
+
+pub async fn handle_sync_reaction(ev: OriginalSyncReactionEvent, room: Room) {
+	let (target, reaction) = to_reaction(&room, &ev);
+	with_timeline(&room, |timeline| timeline.add_reaction(target, reaction));
+}
+
+// 同步收到删除事件时清空被删除的消息
+pub async fn handle_sync_redaction(ev: OriginalSyncRoomRedactionEvent, room: Room) {
+	let Some(redacts) = ev.redacts.or(ev.content.redacts) else {
+		return;
+	};
+
+	with_timeline(&room, |timeline| timeline.redact(&redacts));
+}
+
+// 新的房间密钥可能已经到达，重新解密之前失败的消息
+pub async fn retry_decryption(client: &Client) {
+	let Some(user_id) = client.user_id() else {
+		return;
+	};
+
+	let pending: Vec<(OwnedRoomId, Raw<OriginalSyncRoomEncryptedEvent>)> = with_session(user_id, |session| {
+		session.timelines.iter().flat_map(|(room_id, timeline)| {
+			timeline.items.iter()
+				.chain(timeline.edits.values())
+				.filter_map(|item| item.encrypted.clone())
+				.map(|raw| (room_id.clone(), raw))
+				.collect::<Vec<_>>()
+		}).collect()
+	}).unwrap_or_default();
+
+	let pending_reactions: Vec<(OwnedRoomId, Raw<OriginalSyncRoomEncryptedEvent>)> = with_session(user_id, |session| {
+		session.timelines.iter_mut().flat_map(|(room_id, timeline)| {
+			std::mem::take(&mut timeline.pending_reactions).into_iter()
+				.map(|raw| (room_id.clone(), raw))
+				.collect::<Vec<_>>()
+		}).collect()
+	}).unwrap_or_default();
+
+	for (room_id, raw) in pending_reactions {
+		if let Some(room) = client.get_room(&room_id) {
+			handle_encrypted_reaction(&room, raw).await;
+		}
+	}
+
+	for (room_id, raw) in pending {
+		let Some(room) = client.get_room(&room_id) else {
+			continue;
+		};
+		let event_id = raw.get_field::<OwnedEventId>("event_id").ok().flatten();
+		let Some(mut item) = decrypt_item(&room, raw).await else {
+			if let Some(event_id) = event_id {
+				with_timeline(&room, |timeline| timeline.remove_placeholder(&event_id));
+			}
+			continue;
+		};
+		if item.decryption_error.is_some() {
+			continue;
+		}
+		let item_event_id = item.event_id.clone();
+
+		with_timeline(&room, |timeline| {
+			if item.replaces.is_some() {
+				timeline.add_edit(item);
+				return;
+			}
+			if let Some(old) = timeline.items.iter_mut().find(|old| old.event_id == item.event_id) {
+				item.thread_count = item.thread_count.max(old.thread_count);
+				*old = item;
+			}
+			if let Some(event_id) = item_event_id {
+				timeline.apply_edit(&event_id);
+			}
+		});
+	}
+}
+
+// 第一次打开房间时加载最近的历史消息
+pub async fn load_history(room_id: OwnedRoomId) {
+	let Some(room) = get_room(&room_id) else {
+		return;
+	};
+
+	let loaded = with_timeline(&room, |timeline| {
+		timeline.prev_batch.is_some() || timeline.reached_start
+	}).unwrap_or_default();
+	if loaded {
+		return;
+	}
+
+	paginate_backwards(room_id).await;
+}
+
+// 加入 `/messages` 或 `/context` 返回的事件，返回其中被回复的消息
+async fn add_events(room: &Room, events: Vec<TimelineEvent>) -> Vec<OwnedEventId> {
+	let mut items = Vec::new();
+	let mut reactions = Vec::new();
+	for ev in events {
+		if let Some(reaction) = reaction_event(room, &ev) {
+			reactions.push(reaction);
+			continue;
+		}
+		if let Some(raw) = encrypted_reaction_event(&ev) {
+			handle_encrypted_reaction(room, raw).await;
+			continue;
+		}
+		if let Some(item) = timeline_event_item(room, ev).await {
+			items.push(item);
+		}
+	}
+	let parents: Vec<OwnedEventId> = items.iter().filter_map(|item| item.in_reply_to.clone()).collect();
+
+	with_timeline(room, |timeline| {
+		// 跳转加载的消息与已加载的消息之间可能有空缺，按时间插入
+		for item in items {
+			timeline.insert(item);
+		}
+		for (target, reaction) in reactions {
+			timeline.add_reaction(target, reaction);
+		}
+	});
+	parents
+}
+
+// 通过 `/messages` 向前加载更早的历史消息
+pub async fn paginate_backwards(room_id: OwnedRoomId) {
+	let Some(room) = get_room(&room_id) else {
+		return;
+	};
+
+	let from = with_timeline(&room, |timeline| {
+		if timeline.paginating || timeline.reached_start {
+			return None;
+		}
+		timeline.paginating = true;
+		Some(timeline.prev_batch.clone())
+	}).flatten();
+	let Some(from) = from else {
+		return;
+	};
+
+	let messages = match room.messages(MessagesOptions::backward().from(from.as_deref())).await {
+		Ok(messages) => messages,
+		Err(e) => {
+			set_error_message(format!("Failed to load history: {}", e));
+			with_timeline(&room, |timeline| timeline.paginating = false);
+			return;
+		}
+	};
+
+	let parents = add_events(&room, messages.chunk).await;
+	with_timeline(&room, |timeline| {
+		timeline.reached_start = messages.end.is_none();
+		timeline.prev_batch = messages.end;
+		timeline.paginating = false;
+	});
+
+	fetch_reply_parents(&room, parents).await;
+}
+
+// 跳转到消息，不在已加载范围内时通过 `/context` 加载它及附近的消息，加载完成后由界面选中
+pub async fn jump_to_event(room_id: OwnedRoomId, event_id: OwnedEventId) {
+	let Some(room) = get_room(&room_id) else {
+		return;
+	};
+
+	let loaded = with_timeline(&room, |timeline| timeline.contains(&event_id)).unwrap_or_default();
+	if !loaded {
+		let mut request = get_context::v3::Request::new(room_id.clone(), event_id.clone());
+		request.limit = UInt::from(JUMP_CONTEXT_LIMIT);
+		let response = match room.client().send(request, None).await {
+			Ok(response) => response,
+			Err(e) => {
+				set_error_message(format!("Failed to load the message: {}", e));
+				return;
+			}
+		};
+		let events = response.events_before.into_iter()
+			.chain(response.event)
+			.chain(response.events_after)
+			.map(TimelineEvent::new)
+			.collect();
+		let parents = add_events(&room, events).await;
+		fetch_reply_parents(&room, parents).await;
+	}
+
+	let found = with_timeline(&room, |timeline| {
+		timeline.items.iter()
+			.find(|item| item.event_id.as_ref() == Some(&event_id))
+			.map(|item| item.thread_root.clone())
+	}).flatten();
+	let Some(thread_root) = found else {
+		set_error_message("Message not found");
+		return;
+	};
+
+	// 话题中的消息只显示在侧边栏，跳转到话题的根消息并打开话题
+	with_timeline(&room, |timeline| {
+		if thread_root.is_some() {
+			timeline.open_thread = thread_root.clone();
+		}
+		timeline.jump_to = Some(thread_root.clone().unwrap_or(event_id));
+	});
+	if let Some(root) = thread_root {
+		load_thread(room_id, root).await;
+	}
+}
+
+pub fn take_jump_target(room_id: &OwnedRoomId) -> Option<OwnedEventId> {
+	let room = get_room(room_id)?;
+	with_timeline(&room, |timeline| timeline.jump_to.take()).flatten()
+}
+
+// 获取被回复的原消息，生成回复时需要它的内容
+async fn reply_parent(room: &Room, event_id: &OwnedEventId) -> Result<message::OriginalRoomMessageEvent, String> {
+	let ev = room.event(event_id).await
+		.map_err(|e| format!("Failed to load the replied message: {}", e))?;
+
+	match ev.event.deserialize_as::<AnySyncTimelineEvent>() {
+		Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(SyncMessageLikeEvent::Original(ev)))) => {
+			Ok(ev.into_full_event(room.room_id().to_owned()))
+		},
+		_ => Err("Only messages can be replied to".to_string()),
+	}
+}
+
+fn set_send_state(room: &Room, txn_id: &TransactionId, event_id: Option<OwnedEventId>, send_state: SendState) {
+	with_timeline(room, |timeline| {
+		if let Some(local_echo) = timeline.find_local_echo(txn_id) {
+			if local_echo.event_id.is_none() {
+				local_echo.event_id = event_id;
+			}
+			local_echo.send_state = send_state;
+		}
+	});
+}
+
+// 发送文本消息，发送完成前先在时间线中显示本地回显
+// thread_root 不为空时发送到该话题，reply_to 不为空时回复该消息
+pub async fn send_message(room_id: OwnedRoomId, body: String, thread_root: Option<OwnedEventId>, reply_to: Option<OwnedEventId>) {
+	let Some(room) = get_room(&room_id) else {
+		return;
+	};
+
+	let txn_id = TransactionId::new();
+	let sender = sender_name(&room, room.own_user_id()).await;
+
+	let mut content = RoomMessageEventContent::text_plain(body.clone());
+	let mut thread_root = thread_root;
+	// 找不到被回复的消息时仍然保留本地回显，避免丢失输入的内容
+	let mut failure = None;
+	if let Some(reply_to) = &reply_to {
+		match reply_parent(&room, reply_to).await {
+			Ok(parent) => {
+				// 回复话题中的消息时留在话题中
+				content = content.make_reply_to(&parent, ForwardThread::Yes, AddMentions::Yes);
+				thread_root = message_relations(content.relates_to.as_ref()).0;
+			},
+			Err(e) => failure = Some(e),
+		}
+	} else if let Some(root) = &thread_root {
+		// 不支持话题的客户端会显示为对话题中最新一条消息的回复
+		let latest = with_timeline(&room, |timeline| {
+			timeline.thread_items(root).last().and_then(|item| item.event_id.clone())
+		}).flatten().unwrap_or_else(|| root.clone());
+		content.relates_to = Some(message::Relation::Thread(Thread::plain(root.clone(), latest)));
+	}
+
+	let msgtype = editable_msgtype(&content.msgtype, reply_to.is_some());
+	with_timeline(&room, |timeline| timeline.push(TimelineItem {
+		event_id: None,
+		txn_id: Some(txn_id.clone()),
+		sender_id: room.own_user_id().to_owned(),
+		sender,
+		timestamp: MilliSecondsSinceUnixEpoch::now(),
+		body,
+		send_state: failure.clone().map_or(SendState::Sending, SendState::Failed),
+		decryption_error: None,
+		thread_root,
+		thread_count: 0,
+		in_reply_to: reply_to,
+		edited: false,
+		redacted: false,
+		replaces: None,
+		msgtype,
+		encrypted: None,
+	}));
+	if let Some(e) = failure {
+		set_error_message(format!("Failed to send message: {}", e));
+		return;
+	}
+
+	match room.send(content).with_transaction_id(&txn_id).await {
+		Ok(response) => {
+			set_send_state(&room, &txn_id, Some(response.event_id), SendState::Sent);
+		},
+		Err(e) => {
+			set_error_message(format!("Failed to send message: {}", e));
+			set_send_state(&room, &txn_id, None, SendState::Failed(e.to_string()));
+		}
+	}
+}
+
+// 可以编辑的消息当前的内容，只能编辑自己发送的消息
+pub fn editable_body(room_id: &OwnedRoomId, event_id: &OwnedEventId) -> Option<String> {
+	let room = get_room(room_id)?;
+	let item = with_timeline(&room, |timeline| timeline.find(event_id).cloned()).flatten()?;
+
+	if item.sender_id != room.own_user_id() {
+		set_error_message("Only your own messages can be edited");
+		return None;
+	}
+	match item.msgtype {
+		Some(msgtype) if !item.redacted && item.decryption_error.is_none() => Some(msgtype.body().to_string()),
+		_ => {
+			set_error_message("This message cannot be edited");
+			None
+		}
+	}
+}
+
+// 通过 m.replace 关系编辑消息，发送成功后更新时间线
+pub async fn edit_message(room_id: OwnedRoomId, event_id: OwnedEventId, body: String) {
+	let Some(room) = get_room(&room_id) else {
+		return;
+	};
+
+	// 保留原消息的类型
+	let msgtype = with_timeline(&room, |timeline| {
+		timeline.find(&event_id).and_then(|item| item.msgtype.clone())
+	}).flatten().and_then(|msgtype| edited_msgtype(&msgtype, body));
+	let Some(msgtype) = msgtype else {
+		set_error_message("This message cannot be edited");
+		return;
+	};
+	let content = RoomMessageEventContent::new(msgtype.clone())
+		.make_replacement(ReplacementMetadata::new(event_id.clone(), None), None);
+
+	set_error_message("");
+	match room.send(content).await {
+		Ok(response) => {
+			let sender = sender_name(&room, room.own_user_id()).await;
+			with_timeline(&room, |timeline| timeline.add_edit(TimelineItem {
+				event_id: Some(response.event_id),
+				txn_id: None,
+				sender_id: room.own_user_id().to_owned(),
+				sender,
+				timestamp: MilliSecondsSinceUnixEpoch::now(),
+				body: message_body(&msgtype, false),
+				send_state: SendState::Sent,
+				decryption_error: None,
+				thread_root: None,
+				thread_count: 0,
+				in_reply_to: None,
+				edited: false,
+				redacted: false,
+				replaces: Some(event_id),
+				msgtype: Some(msgtype),
+				encrypted: None,
+			}));
+		},
+		Err(e) => {
+			set_error_message(format!("Failed to edit message: {}", e));
+		}
+	}
+}
+
+// 删除消息，可以附带原因
+pub async fn redact_message(room_id: OwnedRoomId, event_id: OwnedEventId, reason: Option<String>) {
+	let Some(room) = get_room(&room_id) else {
+		return;
+	};
+
+	set_error_message("");
+	if let Err(e) = room.redact(&event_id, reason.as_deref(), None).await {
+		set_error_message(format!("Failed to delete message: {}", e));
+		return;
+	}
+
+	with_timeline(&room, |timeline| timeline.redact(&event_id));
+}
+
+// 用表情回应消息，已经用同一个表情回应过时删除自己的回应
+pub async fn toggle_reaction(room_id: OwnedRoomId, event_id: OwnedEventId, key: String) {
+	let Some(room) = get_room(&room_id) else {
+		return;
+	};
+
+	let own = with_timeline(&room, |timeline| {
+		timeline.reactions.get(&event_id)?
+			.iter()
+			.find(|reaction| reaction.own && reaction.key == key)
+			.cloned()
+	}).flatten();
+
+	set_error_message("");
+	if let Some(reaction) = own {
+		// 还在发送中的回应没有可以删除的事件
+		let Some(reaction_id) = reaction.event_id else {
+			return;
+		};
+		if let Err(e) = room.redact(&reaction_id, None, None).await {
+			set_error_message(format!("Failed to remove reaction: {}", e));
+			return;
+		}
+		with_timeline(&room, |timeline| timeline.redact(&reaction_id));
+		return;
+	}
+
+	let txn_id = TransactionId::new();
+	with_timeline(&room, |timeline| timeline.add_reaction(event_id.clone(), Reaction {
+		event_id: None,
+		txn_id: Some(txn_id.clone()),
+		key: key.clone(),
+		own: true,
+	}));
+
+	let content = ReactionEventContent::new(Annotation::new(event_id.clone(), key));
+	let result = room.send(content).with_transaction_id(&txn_id).await;
+	with_timeline(&room, |timeline| {
+		let Some(reactions) = timeline.reactions.get_mut(&event_id) else {
+			return;
+		};
+		match &result {
+			Ok(response) => {
+				if let Some(local_echo) = reactions.iter_mut().find(|reaction| reaction.txn_id.as_ref() == Some(&txn_id)) {
+					local_echo.event_id.get_or_insert_with(|| response.event_id.clone());
+				}
+			},
+			Err(_) => reactions.retain(|reaction| reaction.txn_id.as_ref() != Some(&txn_id)),
+		}
+	});
+	if let Err(e) = result {
+		set_error_message(format!("Failed to send reaction: {}", e));
+	}
+}
+
+// 在侧边栏中打开话题，回复需要另外加载
+pub fn open_thread(room_id: OwnedRoomId, root: OwnedEventId) {
+	let Some(room) = get_room(&room_id) else {
+		return;
+	};
+
+	with_timeline(&room, |timeline| {
+		timeline.open_thread = Some(root.clone());
+		timeline.thread_unread.remove(&root);
+		timeline.thread_loading = true;
+	});
+}
+
+// 通过 `/relations` 加载话题中的所有回复
+pub async fn load_thread(room_id: OwnedRoomId, root: OwnedEventId) {
+	let Some(room) = get_room(&room_id) else {
+		return;
+	};
+
+	let mut items = Vec::new();
+	// 根消息可能还没有加载
+	let has_root = with_timeline(&room, |timeline| timeline.contains(&root)).unwrap_or_default();
+	if !has_root {
+		match room.event(&root).await {
+			Ok(ev) => items.extend(timeline_event_item(&room, ev).await),
+			Err(e) => set_error_message(format!("Failed to load thread: {}", e)),
+		}
+	}
+
+	let mut from = None;
+	loop {
+		let mut request = get_relating_events_with_rel_type::v1::Request::new(room_id.clone(), root.clone(), RelationType::Thread);
+		request.from = from.take();
+
+		let response = match room.client().send(request, None).await {
+			Ok(response) => response,
+			Err(e) => {
+				set_error_message(format!("Failed to load thread: {}", e));
+				break;
+			}
+		};
+
+		for raw in response.chunk {
+			if let Some(item) = timeline_event_item(&room, TimelineEvent::new(raw.cast())).await {
+				items.push(item);
+			}
+		}
+
+		from = response.next_batch;
+		if from.is_none() {
+			break;
+		}
+	}
+
+	let parents: Vec<OwnedEventId> = items.iter().filter_map(|item| item.in_reply_to.clone()).collect();
+	with_timeline(&room, |timeline| {
+		let loaded = items.iter().filter(|item| item.thread_root.as_ref() == Some(&root)).count() as u64;
+		for item in items {
+			timeline.insert(item);
+		}
+		if let Some(root) = timeline.items.iter_mut().find(|item| item.event_id.as_ref() == Some(&root)) {
+			root.thread_count = root.thread_count.max(loaded);
+		}
+		timeline.thread_loading = false;
+	});
+
+	fetch_reply_parents(&room, parents).await;
+}
+
+pub fn close_thread(room_id: OwnedRoomId) {
+	let Some(room) = get_room(&room_id) else {
+		return;
+	};
+
+	with_timeline(&room, |timeline| timeline.open_thread = None);
+}
+
+#[cfg(test)]
+mod tests {
+	use matrix_sdk::ruma::{events::relation::InReplyTo, owned_event_id, UInt};
+
+	use super::*;
+
+	fn item(event_id: &str, sender: &str, timestamp: u32, body: &str) -> TimelineItem {
+		TimelineItem {
+			event_id: Some(OwnedEventId::try_from(event_id).unwrap()),
+			txn_id: None,
+			sender_id: OwnedUserId::try_from(sender).unwrap(),
+			sender: sender.to_string(),
+			timestamp: MilliSecondsSinceUnixEpoch(UInt::from(timestamp)),
+			body: body.to_string(),
+			send_state: SendState::Sent,
+			decryption_error: None,
+			thread_root: None,
+			thread_count: 0,
+			in_reply_to: None,
+			edited: false,
+			redacted: false,
+			replaces: None,
+			msgtype: Some(MessageType::text_plain(body)),
+			encrypted: None,
+		}
+	}
+
+	fn reply(event_id: &str, timestamp: u32, root: &str) -> TimelineItem {
+		TimelineItem {
+			thread_root: Some(OwnedEventId::try_from(root).unwrap()),
+			..item(event_id, "@bob:example.org", timestamp, "reply")
+		}
+	}
+
+	fn edit(event_id: &str, sender: &str, timestamp: u32, body: &str, target: &str) -> TimelineItem {
+		TimelineItem {
+			replaces: Some(OwnedEventId::try_from(target).unwrap()),
+			..item(event_id, sender, timestamp, body)
+		}
+	}
+
+	fn reaction(event_id: &str, key: &str, own: bool) -> Reaction {
+		Reaction {
+			event_id: Some(OwnedEventId::try_from(event_id).unwrap()),
+			txn_id: None,
+			key: key.to_string(),
+			own,
+		}
+	}
+
+	fn body(timeline: &Timeline, event_id: &str) -> String {
+		timeline.find(&OwnedEventId::try_from(event_id).unwrap()).unwrap().body.clone()
+	}
+
+	#[test]
+	fn counts_thread_replies() {
+		let mut timeline = Timeline::default();
+		timeline.push(item("$root", "@alice:example.org", 1, "topic"));
+		timeline.push(reply("$reply", 2, "$root"));
+		// 重复收到的回复不重复计数
+		timeline.push(reply("$reply", 2, "$root"));
+
+		assert_eq!(timeline.items[0].thread_count, 1);
+		assert_eq!(timeline.thread_items(&owned_event_id!("$root")).len(), 2);
+	}
+
+	#[test]
+	fn lists_thread_items_in_order() {
+		let mut timeline = Timeline::default();
+		timeline.push(item("$root", "@alice:example.org", 1, "topic"));
+		timeline.push(item("$other", "@alice:example.org", 4, "other"));
+		timeline.push(reply("$late", 5, "$root"));
+		// 较早的回复按时间插入
+		timeline.push(reply("$early", 3, "$root"));
+
+		let ids: Vec<String> = timeline.thread_items(&owned_event_id!("$root")).iter()
+			.filter_map(|item| item.event_id.as_ref().map(|event_id| event_id.to_string()))
+			.collect();
+		assert_eq!(ids, vec!["$root", "$early", "$late"]);
+		assert_eq!(timeline.items[1].event_id, Some(owned_event_id!("$early")));
+	}
+
+	#[test]
+	fn separates_replies_from_thread_fallbacks() {
+		type Relation = message::Relation<message::RoomMessageEventContentWithoutRelation>;
+
+		let reply = Relation::Reply { in_reply_to: InReplyTo::new(owned_event_id!("$parent")) };
+		assert_eq!(message_relations(Some(&reply)), (None, Some(owned_event_id!("$parent"))));
+
+		// 话题中的回退回复只算作话题消息
+		let fallback = Relation::Thread(Thread::plain(owned_event_id!("$root"), owned_event_id!("$latest")));
+		assert_eq!(message_relations(Some(&fallback)), (Some(owned_event_id!("$root")), None));
+
+		let thread_reply = Relation::Thread(Thread::reply(owned_event_id!("$root"), owned_event_id!("$parent")));
+		assert_eq!(
+			message_relations(Some(&thread_reply)),
+			(Some(owned_event_id!("$root")), Some(owned_event_id!("$parent"))),
+		);
+	}
+
+	#[test]
+	fn removes_placeholders_that_are_not_messages() {
+		let mut timeline = Timeline::default();
+		timeline.push(item("$root", "@alice:example.org", 1, "topic"));
+		timeline.push(TimelineItem {
+			decryption_error: Some("the room key has not been received".to_string()),
+			..reply("$sticker", 2, "$root")
+		});
+		timeline.add_edit(TimelineItem {
+			decryption_error: Some("the room key has not been received".to_string()),
+			..edit("$edit", "@alice:example.org", 3, "", "$root")
+		});
+		assert_eq!(timeline.items[0].thread_count, 1);
+
+		timeline.remove_placeholder(&owned_event_id!("$sticker"));
+		timeline.remove_placeholder(&owned_event_id!("$edit"));
+
+		assert_eq!(timeline.items.len(), 1);
+		assert_eq!(timeline.items[0].thread_count, 0);
+		assert!(timeline.edits.is_empty());
+		// 已解密的消息不会被移除
+		timeline.remove_placeholder(&owned_event_id!("$root"));
+		assert_eq!(timeline.items.len(), 1);
+	}
+
+	#[test]
+	fn applies_the_latest_edit() {
+		let mut timeline = Timeline::default();
+		timeline.push(item("$msg", "@alice:example.org", 1, "hello"));
+
+		timeline.add_edit(edit("$edit2", "@alice:example.org", 3, "hello!!", "$msg"));
+		timeline.add_edit(edit("$edit1", "@alice:example.org", 2, "hello!", "$msg"));
+
+		let msg = timeline.find(&owned_event_id!("$msg")).unwrap();
+		assert_eq!(msg.body, "hello!!");
+		assert!(msg.edited);
+		assert_eq!(msg.msgtype.as_ref().map(|msgtype| msgtype.body()), Some("hello!!"));
+	}
+
+	#[test]
+	fn applies_edits_loaded_before_the_message() {
+		let mut timeline = Timeline::default();
+		timeline.insert(edit("$edit", "@alice:example.org", 2, "fixed", "$msg"));
+		assert!(timeline.items.is_empty());
+
+		timeline.insert(item("$msg", "@alice:example.org", 1, "typo"));
+		assert_eq!(body(&timeline, "$msg"), "fixed");
+	}
+
+	#[test]
+	fn ignores_edits_from_other_senders() {
+		let mut timeline = Timeline::default();
+		timeline.push(item("$msg", "@alice:example.org", 1, "hello"));
+		timeline.add_edit(edit("$edit", "@mallory:example.org", 2, "pwned", "$msg"));
+
+		assert_eq!(body(&timeline, "$msg"), "hello");
+		assert!(!timeline.find(&owned_event_id!("$msg")).unwrap().edited);
+	}
+
+	#[test]
+	fn redacts_messages_and_their_edits() {
+		let mut timeline = Timeline::default();
+		timeline.push(item("$msg", "@alice:example.org", 1, "hello"));
+		timeline.add_edit(edit("$edit", "@alice:example.org", 2, "hello!", "$msg"));
+		timeline.add_reaction(owned_event_id!("$msg"), reaction("$like", "👍", false));
+
+		timeline.redact(&owned_event_id!("$msg"));
+
+		let msg = timeline.find(&owned_event_id!("$msg")).unwrap();
+		assert!(msg.redacted);
+		assert!(msg.body.is_empty());
+		assert!(!msg.edited);
+		assert!(msg.msgtype.is_none());
+		assert!(timeline.reactions(&owned_event_id!("$msg")).is_empty());
+
+		// 删除后收到的编辑不再生效
+		timeline.add_edit(edit("$edit2", "@alice:example.org", 3, "back", "$msg"));
+		assert!(body(&timeline, "$msg").is_empty());
+	}
+
+	#[test]
+	fn redacts_reactions() {
+		let mut timeline = Timeline::default();
+		timeline.push(item("$msg", "@alice:example.org", 1, "hello"));
+		timeline.add_reaction(owned_event_id!("$msg"), reaction("$like", "👍", false));
+		timeline.add_reaction(owned_event_id!("$msg"), reaction("$love", "❤️", false));
+
+		timeline.redact(&owned_event_id!("$like"));
+
+		let groups = timeline.reactions(&owned_event_id!("$msg"));
+		assert_eq!(groups, vec![ReactionGroup { key: "❤️".to_string(), count: 1, own: false }]);
+	}
+
+	#[test]
+	fn groups_reactions_by_key() {
+		let mut timeline = Timeline::default();
+		let target = owned_event_id!("$msg");
+		timeline.add_reaction(target.clone(), reaction("$r1", "👍", false));
+		timeline.add_reaction(target.clone(), reaction("$r2", "🎉", false));
+		timeline.add_reaction(target.clone(), reaction("$r3", "👍", true));
+		// 同一个事件只计算一次
+		timeline.add_reaction(target.clone(), reaction("$r1", "👍", false));
+
+		assert_eq!(timeline.reactions(&target), vec![
+			ReactionGroup { key: "👍".to_string(), count: 2, own: true },
+			ReactionGroup { key: "🎉".to_string(), count: 1, own: false },
+		]);
+	}
+
+	#[test]
+	fn replaces_local_echo_reactions() {
+		let mut timeline = Timeline::default();
+		let target = owned_event_id!("$msg");
+		let txn_id = TransactionId::new();
+		timeline.add_reaction(target.clone(), Reaction {
+			event_id: None,
+			txn_id: Some(txn_id.clone()),
+			key: "👍".to_string(),
+			own: true,
+		});
+		timeline.add_reaction(target.clone(), Reaction {
+			txn_id: Some(txn_id),
+			..reaction("$like", "👍", true)
+		});
+
+		assert_eq!(timeline.reactions(&target), vec![ReactionGroup { key: "👍".to_string(), count: 1, own: true }]);
+	}
+}
@@ -0,0 +1,230 @@
+use std::{fmt::Display, future::Future, sync::{Arc, Mutex}};
+
+use matrix_sdk::{
+	ruma::api::client::uiaa::{AuthData, AuthType, Dummy, Password, UiaaInfo, UserIdentifier},
+	Client, HttpError, HttpResult,
+};
+use serde_json::Value;
+use tokio::sync::oneshot;
+use url::Url;
+
+use crate::matrix::{open_browser, MATRIX_CLIENT};
+
+// ruma 中没有单独的条款认证类型
+pub const TERMS_AUTH_TYPE: &str = "m.login.terms";
+
+// 操作需要再次认证时支持的步骤
+const AUTH_STAGES: &[AuthType] = &[AuthType::Password, AuthType::Sso, AuthType::Dummy];
+
+// 哑步骤不需要用户操作，服务器一直不接受时最多自动重试的次数
+const MAX_DUMMY_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UiaaStage {
+	Password,
+	// 在浏览器中完成 SSO 认证的回退页面地址
+	Sso(String),
+	// 需要同意的条款 (名称, 链接)
+	Terms(Vec<(String, String)>),
+}
+
+// 用户在弹窗中的操作
+#[derive(Debug, Clone)]
+pub enum UiaaResponse {
+	Password(String),
+	// 已完成 SSO 或同意条款
+	Continue,
+}
+
+#[derive(Debug, Clone)]
+pub struct UiaaDialog {
+	pub title: String,
+	pub stage: UiaaStage,
+	// 上一次认证失败的原因
+	pub error: Option<String>,
+	sender: Arc<Mutex<Option<oneshot::Sender<Option<UiaaResponse>>>>>,
+}
+
+#[derive(Debug)]
+pub enum UiaaError {
+	Cancelled,
+	Unsupported(Vec<String>),
+	Http(Box<HttpError>),
+	// 服务器反复拒绝自动完成的步骤
+	TooManyAttempts,
+}
+
+impl Display for UiaaError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			UiaaError::Cancelled => write!(f, "cancelled"),
+			UiaaError::Unsupported(stages) => write!(f, "unsupported authentication steps: {}", stages.join(", ")),
+			UiaaError::Http(e) => write!(f, "{}", e),
+			UiaaError::TooManyAttempts => write!(f, "the server kept rejecting the authentication"),
+		}
+	}
+}
+
+fn set_dialog(dialog: Option<UiaaDialog>) {
+	let mut client = MATRIX_CLIENT.lock().unwrap();
+	client.uiaa = dialog;
+}
+
+// 步骤是否在支持的列表中，同意条款的步骤总是支持
+pub fn is_supported(stage: &AuthType, supported: &[AuthType]) -> bool {
+	supported.contains(stage) || stage.as_str() == TERMS_AUTH_TYPE
+}
+
+// 选择第一个所有步骤都支持的流程，返回其中下一个未完成的步骤
+fn next_stage(info: &UiaaInfo) -> Result<Option<AuthType>, UiaaError> {
+	let Some(flow) = info.flows.iter().find(|flow| flow.stages.iter().all(|stage| is_supported(stage, AUTH_STAGES))) else {
+		return Err(UiaaError::Unsupported(
+			info.flows.iter()
+				.flat_map(|flow| flow.stages.iter().map(|stage| stage.to_string()))
+				.collect()
+		));
+	};
+
+	Ok(flow.stages.iter().find(|stage| !info.completed.contains(stage)).cloned())
+}
+
+// 从认证参数中取出需要同意的条款，优先使用英文版本
+pub fn terms_policies(info: &UiaaInfo) -> Vec<(String, String)> {
+	let Ok(params) = serde_json::from_str::<Value>(info.params.get()) else {
+		return Vec::new();
+	};
+	let Some(policies) = params[TERMS_AUTH_TYPE]["policies"].as_object() else {
+		return Vec::new();
+	};
+
+	policies.iter().filter_map(|(id, policy)| {
+		let policy = policy.as_object()?;
+		let translation = policy.get("en")
+			.or_else(|| policy.values().find(|value| value.is_object()))?;
+		let name = translation["name"].as_str().unwrap_or(id).to_string();
+		let url = translation["url"].as_str().unwrap_or_default().to_string();
+		Some((name, url))
+	}).collect()
+}
+
+fn sso_fallback_url(homeserver: Url, session: &str) -> String {
+	let mut url = homeserver;
+	// 接在服务器地址原有的路径后面，服务器可能部署在子路径下
+	if let Ok(mut segments) = url.path_segments_mut() {
+		segments.pop_if_empty().extend(["_matrix", "client", "v3", "auth", "m.login.sso", "fallback", "web"]);
+	}
+	url.query_pairs_mut().append_pair("session", session);
+	url.to_string()
+}
+
+// 显示弹窗并等待用户操作，取消时返回 None
+async fn prompt(title: &str, stage: UiaaStage, error: Option<String>) -> Option<UiaaResponse> {
+	let (sender, receiver) = oneshot::channel();
+	set_dialog(Some(UiaaDialog {
+		title: title.to_string(),
+		stage,
+		error,
+		sender: Arc::new(Mutex::new(Some(sender))),
+	}));
+
+	receiver.await.ok().flatten()
+}
+
+// 弹窗按钮的回调，None 表示取消
+pub fn respond(response: Option<UiaaResponse>) {
+	let dialog = {
+		let mut client = MATRIX_CLIENT.lock().unwrap();
+		client.uiaa.take()
+	};
+	let Some(dialog) = dialog else {
+		return;
+	};
+
+	let sender = dialog.sender.lock().unwrap().take();
+	if let Some(sender) = sender {
+		let _ = sender.send(response);
+	}
+}
+
+// 发送需要用户交互认证的请求，根据服务器返回的流程逐步弹窗认证并重试
+pub async fn with_uiaa<T, F, Fut>(client: &Client, title: &str, request: F) -> Result<T, UiaaError>
+where
+	F: Fn(Option<AuthData>) -> Fut,
+	Fut: Future<Output = HttpResult<T>>,
+{
+	let mut auth = None;
+	let mut dummy_attempts = 0;
+	loop {
+		let e = match request(auth.take()).await {
+			Ok(response) => return Ok(response),
+			Err(e) => e,
+		};
+		let Some(info) = e.as_uiaa_response() else {
+			return Err(UiaaError::Http(Box::new(e)));
+		};
+
+		let session = info.session.clone();
+		let error = info.auth_error.as_ref().map(|error| error.message.clone());
+		let Some(stage) = next_stage(info)? else {
+			return Err(UiaaError::Http(Box::new(e)));
+		};
+
+		auth = Some(match stage {
+			AuthType::Dummy => {
+				dummy_attempts += 1;
+				if dummy_attempts > MAX_DUMMY_ATTEMPTS {
+					return Err(UiaaError::TooManyAttempts);
+				}
+				let mut dummy = Dummy::new();
+				dummy.session = session;
+				AuthData::Dummy(dummy)
+			},
+			AuthType::Password => {
+				let Some(UiaaResponse::Password(password)) = prompt(title, UiaaStage::Password, error).await else {
+					return Err(UiaaError::Cancelled);
+				};
+				let Some(user_id) = client.user_id() else {
+					return Err(UiaaError::Cancelled);
+				};
+				let mut password = Password::new(UserIdentifier::UserIdOrLocalpart(user_id.to_string()), password);
+				password.session = session;
+				AuthData::Password(password)
+			},
+			AuthType::Sso => {
+				// 没有会话时无法使用回退页面
+				let Some(session) = session else {
+					return Err(UiaaError::Unsupported(vec![stage.to_string()]));
+				};
+				let url = sso_fallback_url(client.homeserver(), &session);
+				open_browser(&url);
+				if prompt(title, UiaaStage::Sso(url), error).await.is_none() {
+					return Err(UiaaError::Cancelled);
+				}
+				AuthData::fallback_acknowledgement(session)
+			},
+			_ => {
+				if prompt(title, UiaaStage::Terms(terms_policies(info)), error).await.is_none() {
+					return Err(UiaaError::Cancelled);
+				}
+				match AuthData::new(TERMS_AUTH_TYPE, session, Default::default()) {
+					Ok(auth) => auth,
+					Err(_) => return Err(UiaaError::Unsupported(vec![TERMS_AUTH_TYPE.to_string()])),
+				}
+			}
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn builds_sso_fallback_url_under_path_prefix() {
+		let url = sso_fallback_url(Url::parse("https://example.org/matrix/").unwrap(), "abc");
+		assert_eq!(url, "https://example.org/matrix/_matrix/client/v3/auth/m.login.sso/fallback/web?session=abc");
+
+		let url = sso_fallback_url(Url::parse("https://example.org").unwrap(), "abc");
+		assert_eq!(url, "https://example.org/_matrix/client/v3/auth/m.login.sso/fallback/web?session=abc");
+	}
+}
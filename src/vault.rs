@@ -0,0 +1,155 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{aead::{Aead, KeyInit}, XChaCha20Poly1305, XNonce};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const PBKDF2_ITERATIONS: u32 = 200_000;
+// 用于校验口令是否正确的明文
+const CHECK_PLAINTEXT: &[u8] = b"matrix-tui vault";
+
+// 由口令派生的密钥，只保存在内存中
+#[derive(Clone)]
+pub struct VaultKey([u8; KEY_LEN]);
+
+impl std::fmt::Debug for VaultKey {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("VaultKey(..)")
+	}
+}
+
+impl VaultKey {
+	fn derive(passphrase: &str, salt: &[u8], iterations: u32) -> Self {
+		let mut key = [0u8; KEY_LEN];
+		pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, iterations, &mut key);
+		Self(key)
+	}
+
+	// 返回 base64 编码的 nonce 和密文
+	pub fn encrypt(&self, plaintext: &[u8]) -> String {
+		let cipher = XChaCha20Poly1305::new(&self.0.into());
+		let mut nonce = [0u8; NONCE_LEN];
+		OsRng.fill_bytes(&mut nonce);
+
+		let mut data = nonce.to_vec();
+		data.extend(cipher.encrypt(XNonce::from_slice(&nonce), plaintext).expect("encryption failure"));
+		STANDARD.encode(data)
+	}
+
+	// 口令错误或数据被篡改时返回 None
+	pub fn decrypt(&self, data: &str) -> Option<Vec<u8>> {
+		let data = STANDARD.decode(data).ok()?;
+		if data.len() < NONCE_LEN {
+			return None;
+		}
+
+		let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+		let cipher = XChaCha20Poly1305::new(&self.0.into());
+		cipher.decrypt(XNonce::from_slice(nonce), ciphertext).ok()
+	}
+}
+
+// 保存在存档中的密钥派生参数
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VaultHeader {
+	pub salt: String,
+	pub iterations: u32,
+	pub check: String,
+}
+
+impl VaultHeader {
+	pub fn create(passphrase: &str) -> (Self, VaultKey) {
+		let mut salt = [0u8; SALT_LEN];
+		OsRng.fill_bytes(&mut salt);
+
+		let key = VaultKey::derive(passphrase, &salt, PBKDF2_ITERATIONS);
+		let header = Self {
+			salt: STANDARD.encode(salt),
+			iterations: PBKDF2_ITERATIONS,
+			check: key.encrypt(CHECK_PLAINTEXT),
+		};
+		(header, key)
+	}
+
+	pub fn unlock(&self, passphrase: &str) -> Option<VaultKey> {
+		let salt = STANDARD.decode(&self.salt).ok()?;
+		let key = VaultKey::derive(passphrase, &salt, self.iterations);
+		match key.decrypt(&self.check) {
+			Some(check) if check == CHECK_PLAINTEXT => Some(key),
+			_ => None,
+		}
+	}
+}
+
+// 新建本地加密存储时使用的随机口令
+pub fn random_passphrase() -> String {
+	OsRng.sample_iter(&Alphanumeric)
+		.take(32)
+		.map(char::from)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// 测试时使用较少的迭代次数
+	const TEST_ITERATIONS: u32 = 1000;
+
+	fn test_header(passphrase: &str) -> (VaultHeader, VaultKey) {
+		let salt = [7u8; SALT_LEN];
+		let key = VaultKey::derive(passphrase, &salt, TEST_ITERATIONS);
+		let header = VaultHeader {
+			salt: STANDARD.encode(salt),
+			iterations: TEST_ITERATIONS,
+			check: key.encrypt(CHECK_PLAINTEXT),
+		};
+		(header, key)
+	}
+
+	#[test]
+	fn encrypts_and_decrypts() {
+		let (_, key) = test_header("correct horse");
+		let data = key.encrypt(b"access token");
+		assert_eq!(key.decrypt(&data).as_deref(), Some(&b"access token"[..]));
+	}
+
+	#[test]
+	fn uses_a_new_nonce_each_time() {
+		let (_, key) = test_header("correct horse");
+		assert_ne!(key.encrypt(b"access token"), key.encrypt(b"access token"));
+	}
+
+	#[test]
+	fn unlocks_with_the_right_passphrase() {
+		let (header, key) = test_header("correct horse");
+		let data = key.encrypt(b"access token");
+
+		let unlocked = header.unlock("correct horse").unwrap();
+		assert_eq!(unlocked.decrypt(&data).as_deref(), Some(&b"access token"[..]));
+	}
+
+	#[test]
+	fn rejects_a_wrong_passphrase() {
+		let (header, key) = test_header("correct horse");
+		assert!(header.unlock("battery staple").is_none());
+
+		let data = key.encrypt(b"access token");
+		let wrong = VaultKey::derive("battery staple", &[7u8; SALT_LEN], TEST_ITERATIONS);
+		assert!(wrong.decrypt(&data).is_none());
+	}
+
+	#[test]
+	fn rejects_tampered_data() {
+		let (_, key) = test_header("correct horse");
+		let mut data = STANDARD.decode(key.encrypt(b"access token")).unwrap();
+		let last = data.len() - 1;
+		data[last] ^= 1;
+		assert!(key.decrypt(&STANDARD.encode(&data)).is_none());
+		assert!(key.decrypt("not base64!").is_none());
+		assert!(key.decrypt(&STANDARD.encode([0u8; 4])).is_none());
+	}
+}
@@ -0,0 +1,323 @@
+use futures_util::StreamExt;
+use matrix_sdk::{
+	encryption::verification::{SasState, SasVerification, Verification, VerificationRequest, VerificationRequestState},
+	ruma::events::key::verification::{
+		request::ToDeviceKeyVerificationRequestEvent, start::ToDeviceKeyVerificationStartEvent, VerificationMethod,
+	},
+	ruma::{OwnedDeviceId, OwnedUserId, UserId},
+	Client,
+};
+
+use crate::matrix::{get_client, set_error_message, with_session};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerificationStage {
+	// 收到对方的请求，等待用户接受
+	Incoming,
+	// 等待对方设备响应
+	Waiting,
+	Emojis(Vec<(String, String)>),
+	Decimals(u16, u16, u16),
+	// 已确认，等待对方设备确认
+	Confirmed,
+	Done,
+	Cancelled(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct VerificationDialog {
+	pub other_user: String,
+	pub other_device: Option<String>,
+	pub stage: VerificationStage,
+	request: Option<VerificationRequest>,
+	sas: Option<SasVerification>,
+}
+
+fn set_stage(user_id: &UserId, stage: VerificationStage) {
+	with_session(user_id, |session| {
+		if let Some(dialog) = session.verification.as_mut() {
+			dialog.stage = stage;
+		}
+	});
+}
+
+fn set_dialog(user_id: &UserId, dialog: Option<VerificationDialog>) {
+	with_session(user_id, |session| session.verification = dialog);
+}
+
+fn get_dialog(user_id: &UserId) -> Option<VerificationDialog> {
+	with_session(user_id, |session| session.verification.clone()).flatten()
+}
+
+// 弹窗按钮操作的是当前账号的验证
+fn active_user_id() -> Option<OwnedUserId> {
+	get_client()?.user_id().map(|user_id| user_id.to_owned())
+}
+
+pub async fn handle_verification_request(ev: ToDeviceKeyVerificationRequestEvent, client: Client) {
+	let Some(user_id) = client.user_id().map(|user_id| user_id.to_owned()) else {
+		return;
+	};
+	let Some(request) = client.encryption()
+		.get_verification_request(&ev.sender, &ev.content.transaction_id)
+		.await else {
+			return;
+		};
+
+	set_dialog(&user_id, Some(VerificationDialog {
+		other_user: ev.sender.to_string(),
+		other_device: Some(ev.content.from_device.to_string()),
+		stage: VerificationStage::Incoming,
+		request: Some(request.clone()),
+		sas: None,
+	}));
+
+	tokio::spawn(watch_request(user_id, request));
+}
+
+// 不经过请求直接开始的 SAS 验证
+pub async fn handle_verification_start(ev: ToDeviceKeyVerificationStartEvent, client: Client) {
+	let Some(user_id) = client.user_id().map(|user_id| user_id.to_owned()) else {
+		return;
+	};
+	if let Some(dialog) = get_dialog(&user_id) {
+		if dialog.request.is_some_and(|request| request.flow_id() == ev.content.transaction_id.as_str()) {
+			return;
+		}
+	}
+
+	let Some(Verification::SasV1(sas)) = client.encryption()
+		.get_verification(&ev.sender, ev.content.transaction_id.as_str())
+		.await else {
+			return;
+		};
+
+	set_dialog(&user_id, Some(VerificationDialog {
+		other_user: ev.sender.to_string(),
+		other_device: Some(ev.content.from_device.to_string()),
+		stage: VerificationStage::Incoming,
+		request: None,
+		sas: Some(sas),
+	}));
+}
+
+// 向自己的其他设备发起验证
+pub async fn start_self_verification() {
+	let Some(client) = get_client() else {
+		return;
+	};
+	let Some(user_id) = client.user_id().map(|user_id| user_id.to_owned()) else {
+		return;
+	};
+
+	let identity = match client.encryption().get_user_identity(&user_id).await {
+		Ok(Some(identity)) => identity,
+		Ok(None) => {
+			set_error_message("Cross-signing is not set up for this account");
+			return;
+		},
+		Err(e) => {
+			set_error_message(format!("Failed to load own identity: {}", e));
+			return;
+		}
+	};
+
+	let request = match identity.request_verification_with_methods(vec![VerificationMethod::SasV1]).await {
+		Ok(request) => request,
+		Err(e) => {
+			set_error_message(format!("Failed to request verification: {}", e));
+			return;
+		}
+	};
+
+	set_dialog(&user_id, Some(VerificationDialog {
+		other_user: user_id.to_string(),
+		other_device: None,
+		stage: VerificationStage::Waiting,
+		request: Some(request.clone()),
+		sas: None,
+	}));
+
+	tokio::spawn(watch_request(user_id, request));
+}
+
+// 验证设备管理中选中的自己的设备
+pub async fn start_device_verification(device_id: OwnedDeviceId) {
+	let Some(client) = get_client() else {
+		return;
+	};
+	let Some(user_id) = client.user_id().map(|user_id| user_id.to_owned()) else {
+		return;
+	};
+
+	let device = match client.encryption().get_device(&user_id, &device_id).await {
+		Ok(Some(device)) => device,
+		Ok(None) => {
+			set_error_message("The device has no encryption keys");
+			return;
+		},
+		Err(e) => {
+			set_error_message(format!("Failed to load device: {}", e));
+			return;
+		}
+	};
+
+	let request = match device.request_verification_with_methods(vec![VerificationMethod::SasV1]).await {
+		Ok(request) => request,
+		Err(e) => {
+			set_error_message(format!("Failed to request verification: {}", e));
+			return;
+		}
+	};
+
+	set_dialog(&user_id, Some(VerificationDialog {
+		other_user: user_id.to_string(),
+		other_device: Some(device_id.to_string()),
+		stage: VerificationStage::Waiting,
+		request: Some(request.clone()),
+		sas: None,
+	}));
+
+	tokio::spawn(watch_request(user_id, request));
+}
+
+async fn watch_request(user_id: OwnedUserId, request: VerificationRequest) {
+	let mut changes = request.changes();
+	while let Some(state) = changes.next().await {
+		match state {
+			VerificationRequestState::Ready { .. } => {
+				match request.start_sas().await {
+					Ok(Some(sas)) => set_sas(&user_id, sas),
+					Ok(None) => {},
+					Err(e) => set_stage(&user_id, VerificationStage::Cancelled(e.to_string())),
+				}
+			},
+			VerificationRequestState::Transitioned { verification: Verification::SasV1(sas) } => {
+				set_sas(&user_id, sas);
+			},
+			VerificationRequestState::Cancelled(info) => {
+				set_stage(&user_id, VerificationStage::Cancelled(info.reason().to_string()));
+				break;
+			},
+			VerificationRequestState::Done => break,
+			_ => {}
+		}
+	}
+}
+
+// 记录 SAS 验证并监听其状态，每个对话框只监听一次
+fn set_sas(user_id: &UserId, sas: SasVerification) {
+	let updated = with_session(user_id, |session| {
+		let Some(dialog) = session.verification.as_mut() else {
+			return false;
+		};
+		if dialog.sas.is_some() {
+			return false;
+		}
+		dialog.other_device = Some(sas.other_device().device_id().to_string());
+		dialog.sas = Some(sas.clone());
+		dialog.stage = VerificationStage::Waiting;
+		true
+	}).unwrap_or_default();
+
+	if updated {
+		tokio::spawn(watch_sas(user_id.to_owned(), sas));
+	}
+}
+
+async fn watch_sas(user_id: OwnedUserId, sas: SasVerification) {
+	if sas.started_from_request() && !sas.we_started() {
+		if let Err(e) = sas.accept().await {
+			set_stage(&user_id, VerificationStage::Cancelled(e.to_string()));
+			return;
+		}
+	}
+
+	let mut changes = sas.changes();
+	while let Some(state) = changes.next().await {
+		match state {
+			SasState::KeysExchanged { emojis, decimals } => {
+				if let Some(emojis) = emojis {
+					let emojis = emojis.emojis.iter()
+						.map(|emoji| (emoji.symbol.to_string(), emoji.description.to_string()))
+						.collect();
+					set_stage(&user_id, VerificationStage::Emojis(emojis));
+				} else {
+					set_stage(&user_id, VerificationStage::Decimals(decimals.0, decimals.1, decimals.2));
+				}
+			},
+			SasState::Confirmed => set_stage(&user_id, VerificationStage::Confirmed),
+			SasState::Done { .. } => {
+				set_stage(&user_id, VerificationStage::Done);
+				break;
+			},
+			SasState::Cancelled(info) => {
+				set_stage(&user_id, VerificationStage::Cancelled(info.reason().to_string()));
+				break;
+			},
+			_ => {}
+		}
+	}
+}
+
+// 对话框的确认按钮，根据当前阶段执行接受、确认或关闭
+pub async fn confirm() {
+	let Some(user_id) = active_user_id() else {
+		return;
+	};
+	let Some(dialog) = get_dialog(&user_id) else {
+		return;
+	};
+
+	let result = match dialog.stage {
+		VerificationStage::Incoming => {
+			set_stage(&user_id, VerificationStage::Waiting);
+			if let Some(sas) = dialog.sas {
+				let result = sas.accept().await;
+				tokio::spawn(watch_sas(user_id.clone(), sas));
+				result
+			} else if let Some(request) = dialog.request {
+				request.accept_with_methods(vec![VerificationMethod::SasV1]).await
+			} else {
+				Ok(())
+			}
+		},
+		VerificationStage::Emojis(_) | VerificationStage::Decimals(..) => match dialog.sas {
+			Some(sas) => sas.confirm().await,
+			None => Ok(()),
+		},
+		VerificationStage::Done | VerificationStage::Cancelled(_) => {
+			set_dialog(&user_id, None);
+			Ok(())
+		},
+		VerificationStage::Waiting | VerificationStage::Confirmed => Ok(()),
+	};
+
+	if let Err(e) = result {
+		set_stage(&user_id, VerificationStage::Cancelled(e.to_string()));
+	}
+}
+
+// 对话框的取消按钮，表情不一致时告知对方
+pub async fn cancel() {
+	let Some(user_id) = active_user_id() else {
+		return;
+	};
+	let Some(dialog) = get_dialog(&user_id) else {
+		return;
+	};
+
+	let result = match (&dialog.stage, dialog.sas, dialog.request) {
+		(VerificationStage::Done | VerificationStage::Cancelled(_), _, _) => Ok(()),
+		(VerificationStage::Emojis(_) | VerificationStage::Decimals(..), Some(sas), _) => sas.mismatch().await,
+		(_, Some(sas), _) => sas.cancel().await,
+		(_, None, Some(request)) => request.cancel().await,
+		(_, None, None) => Ok(()),
+	};
+
+	if let Err(e) = result {
+		set_error_message(format!("Failed to cancel verification: {}", e));
+	}
+
+	set_dialog(&user_id, None);
+}
@@ -0,0 +1,33 @@
+use ratatui::{prelude::*, widgets::*};
+
+use crate::app::{App, FocusArea, FOCUSED_COLOR};
+
+// 可以通过焦点选中的按钮
+pub fn button_block(app: &mut App, area: FocusArea, label: &str, rect: Rect, frame: &mut Frame) {
+    let style = if app.current_focus == area {
+        Style::default().fg(FOCUSED_COLOR)
+    } else {
+        Style::default()
+    };
+
+    let button = Paragraph::new(label.bold())
+        .centered()
+        .block(Block::bordered())
+        .style(style);
+
+    app.focus_area_positions.insert(area, rect);
+    frame.render_widget(button, rect);
+}
+
+// 在 area 中央清出一块弹窗区域
+pub fn popup_area(area: Rect, percent_x: u16, height: u16, frame: &mut Frame) -> Rect {
+    let [vertical] = Layout::vertical([Constraint::Length(height)])
+        .flex(layout::Flex::Center)
+        .areas(area);
+    let [popup] = Layout::horizontal([Constraint::Percentage(percent_x)])
+        .flex(layout::Flex::Center)
+        .areas(vertical);
+
+    frame.render_widget(Clear, popup);
+    popup
+}